@@ -0,0 +1,359 @@
+//! `#[derive(ToJObject, FromJObject)]` for the conversion traits in
+//! `jllib::json`.
+//!
+//! Structs with named fields become maps and tuple structs become lists. A
+//! struct with a single unnamed field is stored as that field, and a unit
+//! struct as `null`. Enum variants become tagged forms: `"Unit"`,
+//! `["Tuple", a, b]` and `["Struct", {"field": value}]`.
+//!
+//! Generic types aren't supported.
+
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+
+#[proc_macro_derive(ToJObject)]
+pub fn derive_to_jobject(input: TokenStream) -> TokenStream {
+    expand(input, to_jobject)
+}
+
+#[proc_macro_derive(FromJObject)]
+pub fn derive_from_jobject(input: TokenStream) -> TokenStream {
+    expand(input, from_jobject)
+}
+
+const JOBJECT: &str = "::jllib::json::JObject";
+const TO: &str = "::jllib::json::ToJObject::to_jobject";
+const FROM: &str = "::jllib::json::FromJObject::from_jobject";
+
+enum Fields {
+    /// Field names as written, so possibly raw identifiers like `r#type`.
+    Named(Vec<String>),
+    Unnamed(usize),
+    Unit,
+}
+
+struct Variant {
+    name: String,
+    fields: Fields,
+}
+
+enum Item {
+    Struct {
+        name: String,
+        fields: Fields,
+    },
+    Enum {
+        name: String,
+        variants: Vec<Variant>,
+    },
+}
+
+fn expand(input: TokenStream, generate: fn(&Item) -> String) -> TokenStream {
+    let code = match parse_item(input) {
+        Ok(item) => generate(&item),
+        Err(message) => format!("compile_error!({:?});", message),
+    };
+    code.parse().expect("derive generated invalid Rust")
+}
+
+/// The name to use in JL for an identifier: `r#type` is just `type`.
+fn key(ident: &str) -> &str {
+    ident.strip_prefix("r#").unwrap_or(ident)
+}
+
+fn is_punct(tree: &TokenTree, ch: char) -> bool {
+    matches!(tree, TokenTree::Punct(p) if p.as_char() == ch)
+}
+
+fn parse_item(input: TokenStream) -> Result<Item, String> {
+    let mut tokens = strip_prefix(input.into_iter().collect()).into_iter();
+    let keyword = match tokens.next() {
+        Some(TokenTree::Ident(i)) => i.to_string(),
+        _ => return Err("expected a struct or enum".to_string()),
+    };
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(i)) => i.to_string(),
+        _ => return Err(format!("expected a name after `{}`", keyword)),
+    };
+    let body = tokens.next();
+    if body.as_ref().is_some_and(|t| is_punct(t, '<')) {
+        return Err("deriving JObject conversions for generic types isn't supported".to_string());
+    }
+
+    match (keyword.as_str(), body) {
+        ("struct", body) => {
+            let fields = match body {
+                Some(TokenTree::Group(g)) => fields(g.delimiter(), g.stream())?,
+                _ => Fields::Unit,
+            };
+            Ok(Item::Struct { name, fields })
+        }
+        ("enum", Some(TokenTree::Group(g))) if g.delimiter() == Delimiter::Brace => {
+            let variants = split_commas(g.stream())
+                .into_iter()
+                .map(variant)
+                .collect::<Result<_, _>>()?;
+            Ok(Item::Enum { name, variants })
+        }
+        _ => Err(format!(
+            "can't derive JObject conversions for `{}`",
+            keyword
+        )),
+    }
+}
+
+/// Drops leading attributes and visibility.
+fn strip_prefix(tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let mut i = 0;
+    loop {
+        match tokens.get(i) {
+            Some(t) if is_punct(t, '#') => i += 2,
+            Some(TokenTree::Ident(ident)) if ident.to_string() == "pub" => {
+                i += 1;
+                if let Some(TokenTree::Group(g)) = tokens.get(i) {
+                    if g.delimiter() == Delimiter::Parenthesis {
+                        i += 1;
+                    }
+                }
+            }
+            _ => return tokens[i.min(tokens.len())..].to_vec(),
+        }
+    }
+}
+
+/// Splits on top-level commas. Commas inside groups are already hidden by
+/// the token tree, but the ones in `HashMap<K, V>` aren't.
+fn split_commas(stream: TokenStream) -> Vec<Vec<TokenTree>> {
+    let mut parts = vec![Vec::new()];
+    let mut depth = 0;
+    let mut after_dash = false;
+    for tree in stream {
+        let dash = is_punct(&tree, '-');
+        match &tree {
+            t if is_punct(t, '<') => depth += 1,
+            // `->` in a fn pointer type isn't a closing bracket.
+            t if is_punct(t, '>') && !after_dash => depth -= 1,
+            t if is_punct(t, ',') && depth == 0 => {
+                parts.push(Vec::new());
+                after_dash = false;
+                continue;
+            }
+            _ => {}
+        }
+        after_dash = dash;
+        parts.last_mut().unwrap().push(tree);
+    }
+    parts.retain(|p| !p.is_empty());
+    parts
+}
+
+fn fields(delimiter: Delimiter, stream: TokenStream) -> Result<Fields, String> {
+    match delimiter {
+        Delimiter::Brace => {
+            let names = split_commas(stream)
+                .into_iter()
+                .map(|field| match strip_prefix(field).first() {
+                    Some(TokenTree::Ident(i)) => Ok(i.to_string()),
+                    _ => Err("expected a field name".to_string()),
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(Fields::Named(names))
+        }
+        Delimiter::Parenthesis => Ok(Fields::Unnamed(split_commas(stream).len())),
+        _ => Err("unexpected fields".to_string()),
+    }
+}
+
+fn variant(tokens: Vec<TokenTree>) -> Result<Variant, String> {
+    let tokens = strip_prefix(tokens);
+    let name = match tokens.first() {
+        Some(TokenTree::Ident(i)) => i.to_string(),
+        _ => return Err("expected a variant name".to_string()),
+    };
+    let fields = match tokens.get(1) {
+        Some(TokenTree::Group(g)) => fields(g.delimiter(), g.stream())?,
+        _ => Fields::Unit,
+    };
+    Ok(Variant { name, fields })
+}
+
+/// `("a".to_string(), to_jobject(<access>a)), ...` for a map of fields.
+fn entries(names: &[String], access: &str) -> String {
+    let entries: Vec<String> = names
+        .iter()
+        .map(|n| format!("({:?}.to_string(), {}({}{}))", key(n), TO, access, n))
+        .collect();
+    format!("{}::from_entries(vec![{}])", JOBJECT, entries.join(", "))
+}
+
+/// `a: from_jobject(m.get("a") or null)?, ...` to rebuild named fields.
+fn named_from(names: &[String], map: &str) -> String {
+    names
+        .iter()
+        .map(|n| {
+            format!(
+                "{}: {}({}.get({:?}).unwrap_or(&{}::Null))?,",
+                n,
+                FROM,
+                map,
+                key(n),
+                JOBJECT
+            )
+        })
+        .collect()
+}
+
+fn to_jobject(item: &Item) -> String {
+    let (name, body) = match item {
+        Item::Struct { name, fields } => {
+            let body = match fields {
+                Fields::Named(names) => entries(names, "&self."),
+                Fields::Unnamed(1) => format!("{}(&self.0)", TO),
+                Fields::Unnamed(n) => {
+                    let items: Vec<String> =
+                        (0..*n).map(|i| format!("{}(&self.{})", TO, i)).collect();
+                    format!("{}::List(vec![{}])", JOBJECT, items.join(", "))
+                }
+                Fields::Unit => format!("{}::Null", JOBJECT),
+            };
+            (name, body)
+        }
+        Item::Enum { name, variants } => {
+            let arms: String = variants
+                .iter()
+                .map(|v| {
+                    let tag = format!("{}::String({:?}.to_string())", JOBJECT, key(&v.name));
+                    match &v.fields {
+                        Fields::Unit => format!("{}::{} => {},", name, v.name, tag),
+                        Fields::Unnamed(n) => {
+                            let binds: Vec<String> = (0..*n).map(|i| format!("f{}", i)).collect();
+                            let items: Vec<String> =
+                                binds.iter().map(|b| format!("{}({})", TO, b)).collect();
+                            let refs: Vec<String> =
+                                binds.iter().map(|b| format!("ref {}", b)).collect();
+                            format!(
+                                "{}::{}({}) => {}::List(vec![{}, {}]),",
+                                name,
+                                v.name,
+                                refs.join(", "),
+                                JOBJECT,
+                                tag,
+                                items.join(", ")
+                            )
+                        }
+                        Fields::Named(names) => format!(
+                            "{}::{} {{ {} }} => {}::List(vec![{}, {}]),",
+                            name,
+                            v.name,
+                            names
+                                .iter()
+                                .map(|n| format!("ref {}", n))
+                                .collect::<Vec<_>>()
+                                .join(", "),
+                            JOBJECT,
+                            tag,
+                            entries(names, "")
+                        ),
+                    }
+                })
+                .collect();
+            (name, format!("match *self {{ {} }}", arms))
+        }
+    };
+    format!(
+        "impl ::jllib::json::ToJObject for {} {{
+            fn to_jobject(&self) -> {} {{ {} }}
+        }}",
+        name, JOBJECT, body
+    )
+}
+
+fn from_jobject(item: &Item) -> String {
+    let (name, body) = match item {
+        Item::Struct { name, fields } => {
+            let body = match fields {
+                Fields::Named(names) => format!(
+                    "if !matches!(o, {}::Map(_)) {{ return None; }} Some({} {{ {} }})",
+                    JOBJECT,
+                    name,
+                    named_from(names, "o")
+                ),
+                Fields::Unnamed(1) => format!("Some({}({}(o)?))", name, FROM),
+                Fields::Unnamed(n) => {
+                    let items: Vec<String> =
+                        (0..*n).map(|i| format!("{}(&l[{}])?", FROM, i)).collect();
+                    format!(
+                        "match o {{ {}::List(l) if l.len() == {} => Some({}({})), _ => None }}",
+                        JOBJECT,
+                        n,
+                        name,
+                        items.join(", ")
+                    )
+                }
+                Fields::Unit => format!(
+                    "match o {{ {}::Null => Some({}), _ => None }}",
+                    JOBJECT, name
+                ),
+            };
+            (name, body)
+        }
+        Item::Enum { name, variants } => {
+            let units: String = variants
+                .iter()
+                .filter(|v| matches!(v.fields, Fields::Unit))
+                .map(|v| format!("{:?} => Some({}::{}),", key(&v.name), name, v.name))
+                .collect();
+            let tagged: String = variants
+                .iter()
+                .map(|v| match &v.fields {
+                    Fields::Unit => String::new(),
+                    Fields::Unnamed(n) => {
+                        let binds: Vec<String> = (0..*n).map(|i| format!("f{}", i)).collect();
+                        let items: Vec<String> =
+                            binds.iter().map(|b| format!("{}({})?", FROM, b)).collect();
+                        format!(
+                            "({:?}, [{}]) => Some({}::{}({})),",
+                            key(&v.name),
+                            binds.join(", "),
+                            name,
+                            v.name,
+                            items.join(", ")
+                        )
+                    }
+                    Fields::Named(names) => format!(
+                        "({:?}, [m @ {}::Map(_)]) => Some({}::{} {{ {} }}),",
+                        key(&v.name),
+                        JOBJECT,
+                        name,
+                        v.name,
+                        named_from(names, "m")
+                    ),
+                })
+                .collect();
+            let body = format!(
+                "match o {{
+                    {j}::String(tag) => match tag.as_str() {{ {units} _ => None }},
+                    {j}::List(l) => match l.split_first() {{
+                        Some(({j}::String(tag), rest)) => match (tag.as_str(), rest) {{ {tagged} _ => None }},
+                        _ => None,
+                    }},
+                    _ => None,
+                }}",
+                j = JOBJECT,
+                units = units,
+                tagged = tagged
+            );
+            (name, body)
+        }
+    };
+    format!(
+        "impl ::jllib::json::FromJObject for {name} {{
+            fn typename() -> String {{ {name:?}.to_string() }}
+
+            #[allow(unused_variables)]
+            fn from_jobject(o: &{j}) -> Option<Self> {{ {body} }}
+        }}",
+        name = name,
+        j = JOBJECT,
+        body = body
+    )
+}
@@ -0,0 +1,114 @@
+//! Helpers for writing builtins: arity checks, typed arguments and calling
+//! function values.
+
+use crate::error::Error;
+use crate::eval::{apply, eval, Environment};
+use crate::json::{FromJObject, JObject};
+
+/// Fails with `bad-arity` unless there are exactly `n` arguments.
+pub fn arity(args: &[JObject], n: usize) -> Result<(), Error> {
+    if args.len() != n {
+        return Err(Error::arity(args.len(), n));
+    }
+    Ok(())
+}
+
+/// Fails with `bad-arity` unless there are at least `n` arguments.
+pub fn min_arity(args: &[JObject], n: usize) -> Result<(), Error> {
+    if args.len() < n {
+        return Err(Error::new("bad-arity", format!("{} < {}", args.len(), n)));
+    }
+    Ok(())
+}
+
+/// Converts an already evaluated argument, failing with `bad-type`.
+/// `index` is 0-based and only used for the message.
+pub fn convert<T: FromJObject>(fname: &str, index: usize, o: &JObject) -> Result<T, Error> {
+    T::from_jobject(o).ok_or_else(|| {
+        Error::new(
+            "bad-type",
+            format!(
+                "{}: argument {} should be {}, not {} {}",
+                fname,
+                index + 1,
+                T::typename(),
+                o.typename(),
+                o
+            ),
+        )
+    })
+}
+
+/// Evaluates `args[index]` and converts it.
+pub fn arg<T: FromJObject>(
+    env: &mut Environment,
+    fname: &str,
+    args: &[JObject],
+    index: usize,
+) -> Result<T, Error> {
+    let o = eval(env, &args[index])?;
+    convert(fname, index, &o)
+}
+
+/// A tuple of argument types that a builtin's arguments are read into.
+pub trait FromArgs: Sized {
+    fn from_args(env: &mut Environment, fname: &str, args: &[JObject]) -> Result<Self, Error>;
+}
+
+macro_rules! impl_from_args {
+    ($n:expr; $($t:ident $i:tt),*) => {
+        impl<$($t: FromJObject),*> FromArgs for ($($t,)*) {
+            #[allow(unused_variables)]
+            fn from_args(
+                env: &mut Environment,
+                fname: &str,
+                args: &[JObject],
+            ) -> Result<Self, Error> {
+                arity(args, $n)?;
+                Ok(($(arg::<$t>(env, fname, args, $i)?,)*))
+            }
+        }
+    };
+}
+
+impl_from_args!(0;);
+impl_from_args!(1; A 0);
+impl_from_args!(2; A 0, B 1);
+impl_from_args!(3; A 0, B 1, C 2);
+impl_from_args!(4; A 0, B 1, C 2, D 3);
+
+/// Evaluates and converts all of a builtin's arguments at once.
+pub fn typed<T: FromArgs>(
+    env: &mut Environment,
+    fname: &str,
+    args: &[JObject],
+) -> Result<T, Error> {
+    T::from_args(env, fname, args)
+}
+
+/// Anything that can be called: a function, a macro, or the name of one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Callable(pub JObject);
+
+impl Callable {
+    /// Calls this with already evaluated arguments.
+    pub fn call(&self, env: &mut Environment, args: Vec<JObject>) -> Result<JObject, Error> {
+        apply(env, &self.0, args)
+    }
+}
+
+impl FromJObject for Callable {
+    fn typename() -> String {
+        "Func".to_string()
+    }
+
+    fn from_jobject(o: &JObject) -> Option<Self> {
+        match o {
+            JObject::Func { .. }
+            | JObject::Macro { .. }
+            | JObject::String(_)
+            | JObject::Symbol(_) => Some(Callable(o.clone())),
+            _ => None,
+        }
+    }
+}
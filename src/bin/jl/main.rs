@@ -1,10 +1,12 @@
 use std::env::args;
 use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
 
 use jllib::{
     eval::{self, Environment},
     json::{self, JObject},
-    stdlib,
+    locate::locate,
+    serialize, stdlib,
 };
 
 fn main() -> Result<(), io::Error> {
@@ -21,36 +23,73 @@ fn main() -> Result<(), io::Error> {
     );
 
     env.symbols.insert("pi".to_string(), JObject::Number(3));
-    env.symbols.insert(
-        "pie".to_string(),
-        JObject::String("3.14159265359".to_string()),
-    );
+    env.symbols
+        .insert("pie".to_string(), JObject::Float(std::f64::consts::PI));
 
     stdlib::load_mod(env);
 
-    if args().len() == 1 {
-        mainloop(env)
-    } else if args().len() == 2 {
-        let args: Vec<String> = args().collect();
-        return eval::run_file(env, &args[1]);
-    } else {
-        println!("invald # of args: {}", args().len())
+    let mut path = None;
+    for arg in args().skip(1) {
+        match arg.as_str() {
+            "--symbols" => env.parse_options.symbols = true,
+            "--strict" => env.strict = true,
+            flag if flag.starts_with("--") => {
+                eprintln!("unknown option: {}", flag);
+                std::process::exit(2);
+            }
+            _ if path.is_some() => {
+                eprintln!("usage: jl [--strict] [--symbols] [file]");
+                std::process::exit(2);
+            }
+            _ => path = Some(arg),
+        }
+    }
+
+    match path {
+        None => mainloop(env),
+        Some(path) => {
+            if let Err(err) = eval::run_file(env, &path) {
+                eprintln!("{}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
     }
     Ok(())
 }
 
-fn readline() -> String {
+fn readline() -> Option<String> {
     print!("; ");
-    io::stdout().flush().unwrap();
+    io::stdout().flush().ok()?;
     let mut line = String::new();
-    io::stdin().read_line(&mut line).unwrap();
-    line
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line),
+    }
 }
 
 fn mainloop(env: &mut Environment) {
-    loop {
-        let program = json::parse(&readline());
-        let res: JObject = eval::eval(env, &program);
-        println!("{}", res)
+    while let Some(line) = readline() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match json::try_parse_spanned(&line, env.parse_options) {
+            // A panicking builtin shouldn't take the session down with it.
+            Ok((program, spans)) => {
+                match panic::catch_unwind(AssertUnwindSafe(|| eval::eval(env, &program))) {
+                    Ok(Ok(res)) => {
+                        println!("{}", serialize::compact_with(&res, env.parse_options))
+                    }
+                    Ok(Err(err)) => println!(
+                        "error: {}",
+                        locate(err, &program, &spans, &line, env.parse_options)
+                    ),
+                    Err(_) => {
+                        env.reset_run_state();
+                        println!("error: evaluation panicked")
+                    }
+                }
+            }
+            Err(err) => println!("{}", err),
+        }
     }
 }
@@ -0,0 +1,62 @@
+use std::fmt;
+
+use crate::json::{new_list, JObject, ToJObject};
+
+/// Why evaluation stopped. Errors unwind out of `eval` until a `try` form
+/// catches them or they reach the caller.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// A value raised with `throw`.
+    Thrown(JObject),
+    /// An error raised by the interpreter or a builtin, e.g. `bad-arity`.
+    Raised { kind: String, message: String },
+}
+
+impl Error {
+    pub fn new(kind: &str, message: impl Into<String>) -> Error {
+        Error::Raised {
+            kind: kind.to_string(),
+            message: message.into(),
+        }
+    }
+
+    pub fn arity(got: usize, expected: usize) -> Error {
+        Error::new("bad-arity", format!("{} != {}", got, expected))
+    }
+
+    /// Whether `try` may handle this error. Running out of a resource
+    /// limit can't be caught, so untrusted code can't ignore it.
+    pub fn is_catchable(&self) -> bool {
+        self.kind() != "limit-exceeded"
+    }
+
+    /// The kind of a raised error, or `"thrown"` for a thrown value.
+    pub fn kind(&self) -> &str {
+        match self {
+            Error::Thrown(_) => "thrown",
+            Error::Raised { kind, .. } => kind,
+        }
+    }
+}
+
+impl ToJObject for Error {
+    /// The value a `catch` handler sees: thrown values as they were thrown,
+    /// everything else as `["error", kind, message]`.
+    fn to_jobject(&self) -> JObject {
+        match self {
+            Error::Thrown(o) => o.clone(),
+            Error::Raised { kind, message } => new_list(&["error", kind, message]),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Thrown(o) => write!(f, "uncaught {}", o),
+            Error::Raised { kind, message } => write!(f, "{}: {}", kind, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,127 +1,570 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
+use std::rc::Rc;
+use std::time::Instant;
 
+use crate::args::FromArgs;
+use crate::error::Error;
 use crate::json;
-use crate::json::JObject;
+use crate::json::{Closure, JObject, ParseOptions, ToJObject};
+use crate::locate::locate;
+use crate::map::OrderedMap;
+use crate::stdlib;
 
-type JlFn = fn(&mut Environment, &[JObject]) -> JObject;
+pub type JlFn = Rc<dyn Fn(&mut Environment, &[JObject]) -> Result<JObject, Error>>;
+pub type SpecialFn = Rc<dyn Fn(&mut Environment, &[JObject]) -> Result<Tail, Error>>;
+
+/// What a special form hands back to `eval`.
+pub enum Tail {
+    /// The form is done and this is its value.
+    Return(JObject),
+    /// The form's value is this expression, evaluated in the current scope.
+    /// `eval` loops on it instead of recursing, so tail calls don't grow
+    /// the stack.
+    Eval(JObject),
+}
+
+#[derive(Clone)]
+pub enum Builtin {
+    Native(JlFn),
+    Special(SpecialFn),
+}
+
+impl fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Builtin::Native(_) => write!(f, "Native"),
+            Builtin::Special(_) => write!(f, "Special"),
+        }
+    }
+}
+
+/// A frame of local bindings. Each function call gets a fresh frame whose
+/// parent is the frame the function was created in.
+#[derive(Debug, Default)]
+pub struct Scope {
+    vars: RefCell<HashMap<String, JObject>>,
+    /// Names bound by `set_recursive` to functions that closed over this
+    /// frame. They're stored without their closure, which would be a
+    /// reference cycle, and get it back when looked up.
+    tied: RefCell<HashSet<String>>,
+    parent: Option<Rc<Scope>>,
+}
+
+impl Scope {
+    pub fn new(parent: Option<Rc<Scope>>) -> Rc<Scope> {
+        Rc::new(Scope {
+            vars: RefCell::new(HashMap::new()),
+            tied: RefCell::new(HashSet::new()),
+            parent,
+        })
+    }
+
+    /// Looks `name` up in this frame, then in each enclosing frame.
+    pub fn get(self: &Rc<Self>, name: &str) -> Option<JObject> {
+        if let Some(o) = self.vars.borrow().get(name) {
+            let mut o = o.clone();
+            if self.tied.borrow().contains(name) {
+                if let JObject::Func { closure, .. } | JObject::Macro { closure, .. } = &mut o {
+                    *closure = Closure(Some(self.clone()));
+                }
+            }
+            return Some(o);
+        }
+        self.parent.as_ref()?.get(name)
+    }
+
+    /// Binds `name` in this frame, shadowing any enclosing binding.
+    pub fn set(&self, name: &str, value: JObject) {
+        self.tied.borrow_mut().remove(name);
+        self.vars.borrow_mut().insert(name.to_string(), value);
+    }
+
+    /// Like `set`, for `letrec`, where a function usually closes over the
+    /// frame it's bound in. Such a function is kept without its closure so
+    /// the frame can still be freed. A function that only reaches this
+    /// frame through another one, like one made inside a `let` in the
+    /// binding, still keeps it alive for good.
+    pub fn set_recursive(self: &Rc<Self>, name: &str, mut value: JObject) {
+        if let JObject::Func { closure, .. } | JObject::Macro { closure, .. } = &mut value {
+            if closure.0.as_ref().is_some_and(|c| Rc::ptr_eq(c, self)) {
+                closure.0 = None;
+                self.set(name, value);
+                self.tied.borrow_mut().insert(name.to_string());
+                return;
+            }
+        }
+        self.set(name, value);
+    }
+}
+
+/// The most elements or characters one builtin call may build.
+pub const MAX_LEN: usize = 1 << 24;
+
+/// Bounds on how much work `eval` may do before it gives up with a
+/// `limit-exceeded` error. `None` means unbounded.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// How deeply `eval` may nest. Tail calls don't count.
+    pub max_depth: Option<usize>,
+    /// How many evaluation steps may be taken in total.
+    pub max_steps: Option<u64>,
+    /// When to stop, regardless of progress.
+    pub deadline: Option<Instant>,
+}
+
+/// What an environment is allowed to load. The default allows everything.
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    /// Stdlib modules `import` may load, or `None` for any of them.
+    pub modules: Option<HashSet<String>>,
+    /// Builtins that are never registered, even by a module that has them.
+    pub denied_builtins: HashSet<String>,
+}
+
+impl Capabilities {
+    /// Only side-effect-free modules, and no `crash`.
+    pub fn pure() -> Capabilities {
+        Capabilities {
+            modules: Some(stdlib::PURE_MODULES.iter().map(|m| m.to_string()).collect()),
+            denied_builtins: HashSet::from(["crash".to_string()]),
+        }
+    }
+
+    pub fn allows_module(&self, name: &str) -> bool {
+        self.modules.as_ref().is_none_or(|m| m.contains(name))
+    }
+
+    pub fn allows_builtin(&self, name: &str) -> bool {
+        !self.denied_builtins.contains(name)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Environment {
     pub symbols: HashMap<String, JObject>,
-    pub builtins: HashMap<String, JlFn>,
+    pub builtins: HashMap<String, Builtin>,
+    /// The innermost local frame, or `None` at the top level.
+    pub scope: Option<Rc<Scope>>,
+    pub limits: Limits,
+    pub capabilities: Capabilities,
+    /// Current nesting of `eval` calls.
+    pub depth: usize,
+    /// Steps taken so far. Reset this to give a new run a fresh budget.
+    pub steps: u64,
+    /// How `run_file` reads source text.
+    pub parse_options: ParseOptions,
+    /// Makes calls to unknown functions and uses of unbound variables
+    /// errors instead of `null` and the string itself.
+    pub strict: bool,
 }
 
 impl Environment {
     pub fn init() -> Environment {
-        let env = Environment {
+        Environment {
             symbols: HashMap::new(),
             builtins: HashMap::new(),
-        };
+            scope: None,
+            limits: Limits::default(),
+            capabilities: Capabilities::default(),
+            depth: 0,
+            steps: 0,
+            parse_options: ParseOptions::default(),
+            strict: false,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Environment {
+        self.limits = limits;
+        self
+    }
+
+    pub fn builder() -> EnvironmentBuilder {
+        EnvironmentBuilder::default()
+    }
+
+    /// Forgets the state of an evaluation that was abandoned part way,
+    /// e.g. by a panic: the local frames, the nesting depth and the steps
+    /// taken.
+    pub fn reset_run_state(&mut self) {
+        self.scope = None;
+        self.depth = 0;
+        self.steps = 0;
+    }
+
+    /// Counts one evaluation step against the limits.
+    fn step(&mut self) -> Result<(), Error> {
+        self.steps += 1;
+        self.check_steps()?;
+        if let Some(deadline) = self.limits.deadline {
+            // Reading the clock is slow compared to a step, so only do it
+            // every so often.
+            if self.steps.is_multiple_of(256) && Instant::now() >= deadline {
+                return Err(Error::new("limit-exceeded", "deadline passed"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts `steps` steps of work done inside a builtin, such as
+    /// backtracking in a regex, against the limits.
+    pub fn charge(&mut self, steps: u64) -> Result<(), Error> {
+        self.steps = self.steps.saturating_add(steps);
+        self.check_steps()?;
+        if let Some(deadline) = self.limits.deadline {
+            if Instant::now() >= deadline {
+                return Err(Error::new("limit-exceeded", "deadline passed"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that a builtin may build a list or string of `len` elements
+    /// or characters, and charges a step for each. Anything over `MAX_LEN`
+    /// is refused even without limits, so it's an error rather than a
+    /// failed allocation that aborts the host.
+    pub fn allocate(&mut self, len: usize) -> Result<(), Error> {
+        if len > MAX_LEN {
+            return Err(Error::new(
+                "limit-exceeded",
+                format!("{} elements is more than {}", len, MAX_LEN),
+            ));
+        }
+        self.charge(len as u64)
+    }
+
+    fn check_steps(&self) -> Result<(), Error> {
+        match self.limits.max_steps {
+            Some(max) if self.steps > max => Err(Error::new(
+                "limit-exceeded",
+                format!("more than {} steps", max),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Resolves `name` against the local frames first, then the globals.
+    pub fn lookup(&self, name: &str) -> Option<JObject> {
+        if let Some(o) = self.scope.as_ref().and_then(|s| s.get(name)) {
+            return Some(o);
+        }
+        self.symbols.get(name).cloned()
+    }
+
+    /// Registers a builtin. It can be any closure, so it may capture host
+    /// state such as a handle or a config.
+    pub fn insert_builtin<F>(&mut self, fname: &str, fbody: F)
+    where
+        F: Fn(&mut Environment, &[JObject]) -> Result<JObject, Error> + 'static,
+    {
+        if !self.capabilities.allows_builtin(fname) {
+            return;
+        }
+        self.builtins
+            .insert(fname.to_string(), Builtin::Native(Rc::new(fbody)));
+    }
+
+    /// Registers a builtin by its signature. The arguments are evaluated and
+    /// converted to `A`, a tuple like `(Vec<JObject>, Callable)`, and wrong
+    /// arity or types are reported before `fbody` runs.
+    pub fn insert_typed<A, R, F>(&mut self, fname: &str, fbody: F)
+    where
+        A: FromArgs,
+        R: ToJObject,
+        F: Fn(&mut Environment, A) -> Result<R, Error> + 'static,
+    {
+        let name = fname.to_string();
+        self.insert_builtin(fname, move |env, args| {
+            let args = A::from_args(env, &name, args)?;
+            Ok(fbody(env, args)?.to_jobject())
+        });
+    }
+
+    /// Registers a builtin that mutates its captured state. A call that
+    /// reaches the same builtin again before returning is an error.
+    pub fn insert_builtin_mut<F>(&mut self, fname: &str, fbody: F)
+    where
+        F: FnMut(&mut Environment, &[JObject]) -> Result<JObject, Error> + 'static,
+    {
+        let fbody = RefCell::new(fbody);
+        let name = fname.to_string();
+        self.insert_builtin(fname, move |env, args| match fbody.try_borrow_mut() {
+            Ok(mut f) => f(env, args),
+            Err(_) => Err(Error::new(
+                "reentrant-builtin",
+                format!("{} called itself", name),
+            )),
+        });
+    }
+
+    /// Registers a form that may leave an expression in tail position for
+    /// `eval` to finish, like the branches of `if`.
+    pub fn insert_special<F>(&mut self, fname: &str, fbody: F)
+    where
+        F: Fn(&mut Environment, &[JObject]) -> Result<Tail, Error> + 'static,
+    {
+        if !self.capabilities.allows_builtin(fname) {
+            return;
+        }
+        self.builtins
+            .insert(fname.to_string(), Builtin::Special(Rc::new(fbody)));
+    }
+}
+
+/// Sets up an `Environment` with chosen limits and capabilities. The core
+/// builtins from `stdlib::load_mod` are loaded too, minus any denied ones.
+#[derive(Debug, Default)]
+pub struct EnvironmentBuilder {
+    limits: Limits,
+    capabilities: Capabilities,
+    parse_options: ParseOptions,
+    strict: bool,
+}
+
+impl EnvironmentBuilder {
+    pub fn limits(mut self, limits: Limits) -> EnvironmentBuilder {
+        self.limits = limits;
+        self
+    }
+
+    pub fn capabilities(mut self, capabilities: Capabilities) -> EnvironmentBuilder {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Restricts the environment to side-effect-free builtins.
+    pub fn pure(self) -> EnvironmentBuilder {
+        self.capabilities(Capabilities::pure())
+    }
+
+    /// Allows `import` of `name`. The first call turns the module list from
+    /// "everything" into an allow-list.
+    pub fn allow_module(mut self, name: &str) -> EnvironmentBuilder {
+        self.capabilities
+            .modules
+            .get_or_insert_with(HashSet::new)
+            .insert(name.to_string());
+        self
+    }
+
+    pub fn parse_options(mut self, options: ParseOptions) -> EnvironmentBuilder {
+        self.parse_options = options;
+        self
+    }
+
+    /// Turns on `Environment::strict`.
+    pub fn strict(mut self) -> EnvironmentBuilder {
+        self.strict = true;
+        self
+    }
+
+    pub fn deny_builtin(mut self, name: &str) -> EnvironmentBuilder {
+        self.capabilities.denied_builtins.insert(name.to_string());
+        self
+    }
+
+    pub fn build(self) -> Environment {
+        let mut env = Environment::init();
+        env.limits = self.limits;
+        env.capabilities = self.capabilities;
+        env.parse_options = self.parse_options;
+        env.strict = self.strict;
+        stdlib::load_mod(&mut env);
         env
     }
+}
 
-    pub fn insert_builtin(
-        &mut self,
-        fname: &str,
-        fbody: fn(&mut Environment, &[JObject]) -> JObject,
-    ) {
-        self.builtins.insert(fname.to_string(), fbody);
+pub fn eval(e: &mut Environment, o: &JObject) -> Result<JObject, Error> {
+    if let Some(max) = e.limits.max_depth {
+        if e.depth >= max {
+            return Err(Error::new(
+                "limit-exceeded",
+                format!("deeper than {} calls", max),
+            ));
+        }
     }
+    // Tail calls replace the current scope, so put the caller's back after.
+    let caller = e.scope.clone();
+    e.depth += 1;
+    let res = eval_loop(e, o);
+    e.depth -= 1;
+    e.scope = caller;
+    res
 }
 
-pub fn eval(e: &mut Environment, o: &JObject) -> JObject {
-    match o {
-        JObject::List(list) => match list.split_first() {
-            Some((
-                JObject::Func {
-                    parameters,
-                    definition,
-                },
-                tl,
-            )) => {
-                let arguments = tl.iter().map(|to_eval| eval(e, to_eval)).collect();
-                apply_f(e, *definition.clone(), parameters.clone(), arguments)
-            }
+fn eval_loop(e: &mut Environment, o: &JObject) -> Result<JObject, Error> {
+    let mut o = Cow::Borrowed(o);
+    loop {
+        e.step()?;
+        let next = match o.as_ref() {
+            JObject::List(list) => match list.split_first() {
+                Some((
+                    JObject::Func {
+                        parameters,
+                        definition,
+                        closure,
+                    },
+                    tl,
+                )) => {
+                    let arguments = tl
+                        .iter()
+                        .map(|to_eval| eval(e, to_eval))
+                        .collect::<Result<_, _>>()?;
+                    e.scope = Some(bind(closure, parameters, arguments));
+                    *definition.clone()
+                }
 
-            Some((
-                JObject::Macro {
-                    parameters: arguments,
-                    definition,
-                },
-                tl,
-            )) => apply_f(e, *definition.clone(), arguments.clone(), tl.to_vec()),
-            Some((JObject::String(s), tl)) => {
-                if e.symbols.contains_key(s) {
-                    let new_head = eval(e, &JObject::String(s.to_string()));
-                    let new_list = [vec![new_head], tl.to_vec()].concat();
-                    return eval(e, &JObject::List(new_list));
+                Some((
+                    JObject::Macro {
+                        parameters: arguments,
+                        definition,
+                        closure,
+                    },
+                    tl,
+                )) => {
+                    e.scope = Some(bind(closure, arguments, tl.to_vec()));
+                    *definition.clone()
                 }
-                let res = call_builtin(e, s, tl);
-                if let Some(o) = res {
-                    o
-                } else {
-                    JObject::Null
+
+                Some((head @ (JObject::String(s) | JObject::Symbol(s)), tl)) => {
+                    // With the `$` sigil a plain string can only name a
+                    // builtin, never a variable.
+                    let plain = matches!(head, JObject::String(_)) && e.parse_options.symbols;
+                    if let Some(new_head) = e.lookup(s).filter(|_| !plain) {
+                        JObject::List([vec![new_head], tl.to_vec()].concat())
+                    } else {
+                        match e.builtins.get(s).cloned() {
+                            Some(Builtin::Native(f)) => return shallow(f(e, tl)?),
+                            Some(Builtin::Special(f)) => match f(e, tl)? {
+                                Tail::Return(o) => return shallow(o),
+                                Tail::Eval(o) => o,
+                            },
+                            None if matches!(head, JObject::Symbol(_)) => {
+                                return Err(unbound("unbound-symbol", s))
+                            }
+                            None if e.strict => return Err(unbound("unknown-function", s)),
+                            None => return Ok(JObject::Null),
+                        }
+                    }
                 }
-            }
 
-            Some((JObject::List(l), tl)) => {
-                // let list = vec![vec![eval(e, h)], tl.to_vec()].concat();
-                let hd = eval(e, &JObject::List(l.to_vec()));
-                let new_list = [vec![hd], tl.to_vec()].concat();
-                eval(e, &JObject::List(new_list))
-            }
+                Some((JObject::List(l), tl)) => {
+                    let hd = eval(e, &JObject::List(l.to_vec()))?;
+                    JObject::List([vec![hd], tl.to_vec()].concat())
+                }
 
-            Some(x) => {
-                println!("1st element of list is not function-like: {:?}", x);
-                JObject::Null
-            }
+                Some((x, _)) => {
+                    return Err(Error::new(
+                        "not-callable",
+                        format!("1st element of list is not function-like: {}", x),
+                    ))
+                }
 
-            None => JObject::Null,
-        },
+                // Nothing to call, so `[]` is just an empty list.
+                None => return Ok(JObject::List(vec![])),
+            },
 
-        JObject::Map(m) => {
-            let mut new_map = HashMap::new();
-            for (k, v) in m {
-                let new_v = eval(e, v);
-                new_map.insert(k.to_string(), Box::new(new_v));
+            JObject::Map(m) => {
+                let mut new_map = OrderedMap::new();
+                for (k, v) in m {
+                    let new_v = eval(e, v)?;
+                    new_map.insert(k.to_string(), Box::new(new_v));
+                }
+                return shallow(JObject::Map(new_map));
             }
-            JObject::Map(new_map)
-        }
 
-        JObject::String(s) => e
-            .symbols
-            .get(s)
-            .unwrap_or(&JObject::String(s.to_string()))
-            .clone(),
+            // When the reader marks variables with `$`, strings are
+            // literals. Otherwise, in strict mode, they have to be bound.
+            JObject::String(_) if e.parse_options.symbols => return Ok(o.into_owned()),
+            JObject::String(s) => match e.lookup(s) {
+                Some(o) => return Ok(o),
+                None if e.strict && !e.builtins.contains_key(s) => {
+                    return Err(unbound("unbound-symbol", s))
+                }
+                None => return Ok(JObject::String(s.to_string())),
+            },
+
+            // A symbol naming a builtin stays a symbol, so it can still be
+            // passed around and called like a function.
+            JObject::Symbol(s) => match e.lookup(s) {
+                Some(o) => return Ok(o),
+                None if e.builtins.contains_key(s) => return Ok(o.into_owned()),
+                None => return Err(unbound("unbound-symbol", s)),
+            },
+
+            o => return Ok(o.clone()),
+        };
+        o = Cow::Owned(next);
+    }
+}
+
+/// Passes on a value a builtin or map literal built, unless it nests
+/// deeper than `json::MAX_NESTING`. Nothing else can make values deeper.
+fn shallow(o: JObject) -> Result<JObject, Error> {
+    if o.nests_deeper_than(json::MAX_NESTING) {
+        return Err(Error::new(
+            "limit-exceeded",
+            format!("nested deeper than {} levels", json::MAX_NESTING),
+        ));
+    }
+    Ok(o)
+}
 
-        e => e.clone(),
+/// Calls a function, macro or builtin name with already evaluated
+/// arguments.
+pub fn apply(e: &mut Environment, f: &JObject, arguments: Vec<JObject>) -> Result<JObject, Error> {
+    if let JObject::Func {
+        parameters,
+        definition,
+        closure,
+    } = f
+    {
+        let caller = e.scope.replace(bind(closure, parameters, arguments));
+        let res = eval(e, definition);
+        e.scope = caller;
+        return res;
     }
+    // Everything else evaluates its arguments itself, so keep them literal.
+    let quoted = arguments
+        .into_iter()
+        .map(|arg| JObject::List(vec!["quote".to_jobject(), arg]));
+    let call = std::iter::once(f.clone()).chain(quoted).collect();
+    eval(e, &JObject::List(call))
 }
 
 pub fn run_file(env: &mut Environment, path: &str) -> Result<(), std::io::Error> {
-    let program = fs::read_to_string(path)?;
-    eval(env, &json::parse(&program));
+    let source = fs::read_to_string(path)?;
+    let (program, spans) = json::try_parse_spanned(&source, env.parse_options)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    eval(env, &program).map_err(|err| {
+        let err = locate(err, &program, &spans, &source, env.parse_options);
+        std::io::Error::other(err.to_string())
+    })?;
     Ok(())
 }
 
-fn apply_f(
-    e: &mut Environment,
-    definition: JObject,
-    parameters: Vec<String>,
-    arguments: Vec<JObject>,
-) -> JObject {
-    let fu = std::iter::zip(parameters, arguments);
-    // TODO: Don't insert this into the global symbol table, add scope.
-    fu.for_each(|(param, arg)| {
-        e.symbols.insert(param, arg.clone());
-    });
-    eval(e, &definition)
+/// An unknown name, e.g. `no variable x`. `locate::locate` reads the name
+/// back out of the message.
+fn unbound(kind: &str, name: &str) -> Error {
+    Error::new(kind, format!("{} {}", unbound_prefix(kind), name))
 }
 
-fn call_builtin(env: &mut Environment, fname: &str, args: &[JObject]) -> Option<JObject> {
-    if env.builtins.contains_key(fname) {
-        return Some(env.builtins.get(fname)?(env, args));
+pub(crate) fn unbound_prefix(kind: &str) -> &'static str {
+    match kind {
+        "unknown-function" => "no function",
+        _ => "no variable",
     }
-    None
+}
+
+/// Makes the frame for a call: parameters bound to arguments, on top of
+/// the scope the function closed over.
+fn bind(closure: &Closure, parameters: &[String], arguments: Vec<JObject>) -> Rc<Scope> {
+    let frame = Scope::new(closure.0.clone());
+    std::iter::zip(parameters, arguments).for_each(|(param, arg)| {
+        frame.set(param, arg);
+    });
+    frame
 }
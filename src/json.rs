@@ -1,206 +1,507 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::rc::Rc;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+use crate::eval::Scope;
+use crate::map::OrderedMap;
+use crate::serialize;
+
+pub use jl_derive::{FromJObject, ToJObject};
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum JObject {
     Null,
     Bool(bool),
     Number(i64),
+    Float(f64),
     String(String),
     List(Vec<JObject>),
-    Map(HashMap<String, Box<JObject>>),
+    Map(OrderedMap<Box<JObject>>),
 
     // other stuff to make json a programming language
-    // Symbol(String),
+    /// A variable reference that must be bound, read from `"$name"` when
+    /// `ParseOptions::symbols` is on. Plain strings fall back to themselves.
+    Symbol(String),
     Func {
         parameters: Vec<String>,
         definition: Box<JObject>,
+        closure: Closure,
     },
     Macro {
         parameters: Vec<String>,
         definition: Box<JObject>,
+        closure: Closure,
     },
 }
 
+impl JObject {
+    /// Whether lists and maps nest more than `limit` levels deep. Stops
+    /// looking past `limit`, so it's safe on values of any depth.
+    pub fn nests_deeper_than(&self, limit: usize) -> bool {
+        match self {
+            JObject::List(l) => limit == 0 || l.iter().any(|x| x.nests_deeper_than(limit - 1)),
+            JObject::Map(m) => limit == 0 || m.values().any(|x| x.nests_deeper_than(limit - 1)),
+            _ => false,
+        }
+    }
+}
+
+/// How deeply lists and maps may nest, in parsed text or in a value built
+/// at run time. Cloning, comparing, printing and dropping a value all
+/// recurse once per level, as does evaluating parsed code, so this is kept
+/// low enough for small thread stacks.
+pub const MAX_NESTING: usize = 128;
+
+/// The local scope a function or macro was created in. Two closures are
+/// equal only if they captured the very same scope, or none.
+#[derive(Clone, Default)]
+pub struct Closure(pub Option<Rc<Scope>>);
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Closure) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        }
+    }
+}
+
+impl Eq for Closure {}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // A scope can hold the function that captured it, so don't recurse.
+        write!(f, "Closure")
+    }
+}
+
+/// Parses the first value in `line`, or returns `Null` if there isn't one.
 pub fn parse(line: &str) -> JObject {
-    let x = line.to_string().chars().collect();
-    Parser::parse(&mut Parser { text: x, i: 0 }).unwrap_or(JObject::Null)
+    Parser::new(line, ParseOptions::default())
+        .parse()
+        .unwrap_or(JObject::Null)
+}
+
+/// Parses `text` as exactly one value, reporting where and why it failed.
+pub fn try_parse(text: &str) -> Result<JObject, ParseError> {
+    try_parse_with(text, ParseOptions::default())
 }
 
+/// Reader conventions on top of plain JSON. All are off by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Read `"$name"` as `Symbol("name")` instead of a string. `"$$"`
+    /// escapes the sigil, so `"$$5"` is the string `"$5"`.
+    pub symbols: bool,
+}
+
+/// Like `try_parse`, with reader conventions turned on.
+pub fn try_parse_with(text: &str, options: ParseOptions) -> Result<JObject, ParseError> {
+    Parser::new(text, options).whole()
+}
+
+/// Like `try_parse_with`, also returning where each part of the value is
+/// in `text`.
+pub fn try_parse_spanned(text: &str, options: ParseOptions) -> Result<(JObject, Span), ParseError> {
+    let mut parser = Parser::new(text, options);
+    parser.spans = Some(vec![Vec::new()]);
+    let o = parser.whole()?;
+    let root = parser.spans.unwrap_or_default().concat().pop();
+    // The parser counts characters, but offsets are in bytes.
+    let mut bytes = Vec::with_capacity(parser.text.len() + 1);
+    bytes.push(0);
+    for c in &parser.text {
+        bytes.push(bytes[bytes.len() - 1] + c.len_utf8());
+    }
+    Ok((o, root.unwrap_or_default().into_bytes(&bytes)))
+}
+
+/// Where a parsed value starts, and where its parts do: a list's elements
+/// or a map's values, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offset in the source text.
+    pub offset: usize,
+    pub children: Vec<Span>,
+}
+
+impl Span {
+    fn into_bytes(self, bytes: &[usize]) -> Span {
+        Span {
+            offset: bytes[self.offset],
+            children: self
+                .children
+                .into_iter()
+                .map(|s| s.into_bytes(bytes))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset of the failure in the source text.
+    pub offset: usize,
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column, counted in characters.
+    pub column: usize,
+    /// What the parser was looking for, e.g. `',' or ']'`.
+    pub expected: String,
+    /// The offending source line with a caret under the column.
+    pub snippet: String,
+}
+
+impl ParseError {
+    fn at(text: &[char], i: usize, expected: &str) -> ParseError {
+        let i = i.min(text.len());
+        let line_start = text[..i]
+            .iter()
+            .rposition(|&c| c == '\n')
+            .map_or(0, |n| n + 1);
+        let line_end = text[i..]
+            .iter()
+            .position(|&c| c == '\n')
+            .map_or(text.len(), |n| i + n);
+        let source_line: String = text[line_start..line_end].iter().collect();
+        // Keep tabs so the caret lines up with the source line.
+        let padding: String = text[line_start..i]
+            .iter()
+            .map(|&c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        ParseError {
+            offset: text[..i].iter().map(|c| c.len_utf8()).sum(),
+            line: text[..i].iter().filter(|&&c| c == '\n').count() + 1,
+            column: i - line_start + 1,
+            expected: expected.to_string(),
+            snippet: format!("{}\n{}^", source_line.trim_end_matches('\r'), padding),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "expected {} at line {}, column {}\n{}",
+            self.expected, self.line, self.column, self.snippet
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 #[derive(Debug)]
 struct Parser {
     text: Vec<char>,
     i: usize,
+    options: ParseOptions,
+    /// How many lists and maps the parser is inside.
+    depth: usize,
+    /// When spans are wanted, those of the values parsed so far inside each
+    /// list or map the parser is in, innermost last.
+    spans: Option<Vec<Vec<Span>>>,
 }
 
 impl Parser {
-    fn parse(&mut self) -> Option<JObject> {
+    fn new(text: &str, options: ParseOptions) -> Parser {
+        Parser {
+            text: text.chars().collect(),
+            i: 0,
+            options,
+            depth: 0,
+            spans: None,
+        }
+    }
+
+    /// Parses exactly one value, with nothing but whitespace after it.
+    fn whole(&mut self) -> Result<JObject, ParseError> {
+        let o = self.parse()?;
         self.ws();
-        self.number()
-            .or_else(|| self.null())
-            .or_else(|| self.bool())
-            .or_else(|| self.string())
-            .or_else(|| self.list())
-            .or_else(|| self.map())
+        if self.peek().is_some() {
+            return Err(self.error("end of input"));
+        }
+        Ok(o)
     }
 
-    fn peek(&mut self) -> Option<char> {
-        self.text.get(self.i).copied()
+    fn error(&self, expected: &str) -> ParseError {
+        ParseError::at(&self.text, self.i, expected)
     }
 
-    fn ws(&mut self) -> Option<JObject> {
-        loop {
-            if self.peek()?.is_whitespace() {
-                self.i += 1;
-            } else {
-                break;
+    fn parse(&mut self) -> Result<JObject, ParseError> {
+        self.ws();
+        let start = self.i;
+        if let Some(spans) = &mut self.spans {
+            spans.push(Vec::new());
+        }
+        let o = self.value()?;
+        if let Some(spans) = &mut self.spans {
+            let children = spans.pop().unwrap_or_default();
+            if let Some(siblings) = spans.last_mut() {
+                siblings.push(Span {
+                    offset: start,
+                    children,
+                });
             }
         }
-        None
+        Ok(o)
     }
 
-    fn list(&mut self) -> Option<JObject> {
-        let mut builder = Vec::new();
+    fn value(&mut self) -> Result<JObject, ParseError> {
+        match self.peek() {
+            Some('[') => self.nested(Parser::list),
+            Some('{') => self.nested(Parser::map),
+            Some('"') => self.string(),
+            Some('t') => self.keyword("true", JObject::Bool(true)),
+            Some('f') => self.keyword("false", JObject::Bool(false)),
+            Some('n') => self.keyword("null", JObject::Null),
+            Some('-' | '0'..='9') => self.number(),
+            _ => Err(self.error("a value")),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text.get(self.i).copied()
+    }
 
-        if let Some('[') = self.peek() {
+    fn ws(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
             self.i += 1;
-        } else {
-            return None;
         }
+    }
 
-        loop {
-            self.ws();
-            if let Some(element) = self.parse() {
-                builder.push(element);
-                self.ws();
-                if let Some(',') = self.peek() {
-                    self.i += 1;
-                } else {
-                    break;
-                }
-            } else {
-                break;
-            }
+    /// Parses a list or map with `inner`, unless that would nest deeper
+    /// than `MAX_NESTING`.
+    fn nested(
+        &mut self,
+        inner: fn(&mut Parser) -> Result<JObject, ParseError>,
+    ) -> Result<JObject, ParseError> {
+        if self.depth == MAX_NESTING {
+            return Err(self.error(&format!("at most {} levels of nesting", MAX_NESTING)));
         }
+        self.depth += 1;
+        let o = inner(self);
+        self.depth -= 1;
+        o
+    }
+
+    fn list(&mut self) -> Result<JObject, ParseError> {
+        let mut builder = Vec::new();
+        self.i += 1;
 
         self.ws();
         if let Some(']') = self.peek() {
             self.i += 1;
-        } else {
-            println!("List not terminated {:?} at index {:?}", self.text, self.i);
+            return Ok(JObject::List(builder));
         }
 
-        Some(JObject::List(builder))
+        loop {
+            builder.push(self.parse()?);
+            self.ws();
+            match self.peek() {
+                Some(',') => self.i += 1,
+                Some(']') => {
+                    self.i += 1;
+                    return Ok(JObject::List(builder));
+                }
+                _ => return Err(self.error("',' or ']'")),
+            }
+        }
     }
 
-    fn map(&mut self) -> Option<JObject> {
-        let mut builder = HashMap::new();
-
-        if let Some('{') = self.peek() {
-            self.i += 1;
-        } else {
-            return None;
-        }
+    fn map(&mut self) -> Result<JObject, ParseError> {
+        let mut builder = OrderedMap::new();
+        self.i += 1;
 
+        self.ws();
         if let Some('}') = self.peek() {
             self.i += 1;
-            return Some(JObject::Map(builder));
+            return Ok(JObject::Map(builder));
         }
 
         loop {
             self.ws();
-            let key = if let Some(JObject::String(s)) = self.string() {
-                Some(s)
-            } else {
-                println!("object key is not string");
-                return None;
-            }?;
-            if self.peek() == Some(':') {
-                self.i += 1;
+            let key = match self.peek() {
+                Some('"') => self.rest_of_string()?,
+                _ => return Err(self.error("a string key")),
+            };
+            self.ws();
+            if self.peek() != Some(':') {
+                return Err(self.error("':'"));
             }
+            self.i += 1;
             let value = self.parse()?;
-            self.ws();
             builder.insert(key, Box::new(value));
-            if self.peek() != Some(',') {
-                break;
+            self.ws();
+            match self.peek() {
+                Some(',') => self.i += 1,
+                Some('}') => {
+                    self.i += 1;
+                    return Ok(JObject::Map(builder));
+                }
+                _ => return Err(self.error("',' or '}'")),
             }
-            self.i += 1;
         }
+    }
 
-        self.ws();
-        if let Some('}') = self.peek() {
+    /// Reads a JSON number. Integers that fit in an `i64` become `Number`,
+    /// everything else becomes `Float`.
+    fn number(&mut self) -> Result<JObject, ParseError> {
+        let start = self.i;
+        let mut float = false;
+
+        if self.peek() == Some('-') {
             self.i += 1;
-        } else {
-            println!("Map not terminated {:?} at index {:?}", self.text, self.i);
+        }
+        match self.peek() {
+            Some('0') => self.i += 1,
+            Some('1'..='9') => self.digits()?,
+            _ => return Err(self.error("a digit")),
+        }
+        if self.peek() == Some('.') {
+            self.i += 1;
+            self.digits()?;
+            float = true;
+        }
+        if let Some('e' | 'E') = self.peek() {
+            self.i += 1;
+            if let Some('+' | '-') = self.peek() {
+                self.i += 1;
+            }
+            self.digits()?;
+            float = true;
         }
 
-        Some(JObject::Map(builder))
-    }
-
-    fn number(&mut self) -> Option<JObject> {
-        let str = self.text[self.i..]
-            .iter()
-            .take_while(|c| (&&'0'..=&&'9').contains(&c))
-            .collect::<String>();
-
-        if str.is_empty() {
-            None
-        } else {
-            self.i += str.len();
-            Some(JObject::Number(str.parse().unwrap()))
+        let str: String = self.text[start..self.i].iter().collect();
+        if !float {
+            if let Ok(n) = str.parse() {
+                return Ok(JObject::Number(n));
+            }
+        }
+        match str.parse::<f64>() {
+            Ok(f) if f.is_finite() => Ok(JObject::Float(f)),
+            _ => {
+                self.i = start;
+                Err(self.error("a number in range"))
+            }
         }
     }
 
-    fn null(&mut self) -> Option<JObject> {
-        let s: String = self.text.iter().skip(self.i).take(4).collect();
-
-        if s == "null" {
-            self.i += 4;
-            Some(JObject::Null)
-        } else {
-            None
+    fn digits(&mut self) -> Result<(), ParseError> {
+        if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            return Err(self.error("a digit"));
+        }
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.i += 1;
         }
+        Ok(())
     }
 
-    fn bool(&mut self) -> Option<JObject> {
-        let t: String = self.text.iter().skip(self.i).take(4).collect();
-        let f: String = self.text.iter().skip(self.i).take(5).collect();
-        if t == "true" {
-            self.i += 4;
-            Some(JObject::Bool(true))
-        } else if f == "false" {
-            self.i += 5;
-            Some(JObject::Bool(false))
+    fn keyword(&mut self, word: &str, value: JObject) -> Result<JObject, ParseError> {
+        let s: String = self.text.iter().skip(self.i).take(word.len()).collect();
+
+        if s == word {
+            self.i += word.len();
+            Ok(value)
         } else {
-            None
+            Err(self.error("a value"))
         }
     }
 
-    fn string(&mut self) -> Option<JObject> {
-        if let Some('\"') = self.peek() {
-            self.i += 1;
-            return Some(JObject::String(self.rest_of_string()));
+    fn string(&mut self) -> Result<JObject, ParseError> {
+        let s = self.rest_of_string()?;
+        if !self.options.symbols {
+            return Ok(JObject::String(s));
         }
-
-        None
+        Ok(match s.strip_prefix('$') {
+            Some(rest) if rest.starts_with('$') => JObject::String(rest.to_string()),
+            Some(name) if !name.is_empty() => JObject::Symbol(name.to_string()),
+            _ => JObject::String(s),
+        })
     }
 
-    fn rest_of_string(&mut self) -> String {
+    /// Reads a string starting at its opening quote, decoding escapes.
+    fn rest_of_string(&mut self) -> Result<String, ParseError> {
         let mut text = String::new();
+        self.i += 1;
         loop {
-            if let Some(ch) = self.peek() {
-                if ch == '"' {
+            match self.peek() {
+                Some('"') => {
+                    self.i += 1;
+                    return Ok(text);
+                }
+                Some('\\') => {
                     self.i += 1;
-                    break;
+                    text.push(self.escape()?);
+                    continue;
                 }
-                text.push(ch);
-            } else {
-                println!("Missing quote, continuing");
-                break;
+                Some(ch) if ch < ' ' => return Err(self.error("an escaped control character")),
+                Some(ch) => text.push(ch),
+                None => return Err(self.error("'\"'")),
             }
             self.i += 1
         }
-        text
+    }
+
+    /// Decodes the escape sequence following a backslash.
+    fn escape(&mut self) -> Result<char, ParseError> {
+        let ch = match self.peek() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                self.i += 1;
+                return self.unicode_escape();
+            }
+            _ => return Err(self.error("an escape sequence")),
+        };
+        self.i += 1;
+        Ok(ch)
+    }
+
+    /// Decodes the `XXXX` of a `\uXXXX` escape, joining surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, ParseError> {
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            let start = self.i;
+            if self.peek() != Some('\\') || self.text.get(self.i + 1) != Some(&'u') {
+                return Err(self.error("a low surrogate escape"));
+            }
+            self.i += 2;
+            let low = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                self.i = start;
+                return Err(self.error("a low surrogate escape"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        match char::from_u32(code) {
+            Some(ch) => Ok(ch),
+            None => {
+                self.i -= 4;
+                Err(self.error("a unicode scalar value"))
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            match self.peek().and_then(|c| c.to_digit(16)) {
+                Some(digit) => code = code * 16 + digit,
+                None => return Err(self.error("a hex digit")),
+            }
+            self.i += 1;
+        }
+        Ok(code)
     }
 }
 
@@ -214,12 +515,71 @@ impl JObject {
         JObject::Func {
             parameters: arguments.iter().map(|&arg| arg.to_string()).collect(),
             definition: Box::new(body),
+            closure: Closure::default(),
         }
     }
     pub fn new_macro(arguments: Vec<&str>, body: JObject) -> JObject {
         JObject::Macro {
             parameters: arguments.iter().map(|&arg| arg.to_string()).collect(),
             definition: Box::new(body),
+            closure: Closure::default(),
+        }
+    }
+
+    /// Sets the scope a function or macro closes over. Other values are
+    /// returned unchanged.
+    pub fn capturing(self, scope: Option<Rc<Scope>>) -> JObject {
+        match self {
+            JObject::Func {
+                parameters,
+                definition,
+                ..
+            } => JObject::Func {
+                parameters,
+                definition,
+                closure: Closure(scope),
+            },
+            JObject::Macro {
+                parameters,
+                definition,
+                ..
+            } => JObject::Macro {
+                parameters,
+                definition,
+                closure: Closure(scope),
+            },
+            o => o,
+        }
+    }
+
+    /// Builds a map from key-value pairs, keeping the last of any duplicates.
+    pub fn from_entries(entries: Vec<(String, JObject)>) -> JObject {
+        JObject::Map(entries.into_iter().map(|(k, v)| (k, Box::new(v))).collect())
+    }
+
+    /// Looks up `key` if this is a map.
+    pub fn get(&self, key: &str) -> Option<&JObject> {
+        match self {
+            JObject::Map(m) => m.get(key).map(|v| &**v),
+            _ => None,
+        }
+    }
+
+    /// The name a string or symbol refers to, for forms like `def` that
+    /// take a variable name.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            JObject::String(s) | JObject::Symbol(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The value of a `Number` or `Float` as a float.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JObject::Number(n) => Some(*n as f64),
+            JObject::Float(n) => Some(*n),
+            _ => None,
         }
     }
 
@@ -228,57 +588,80 @@ impl JObject {
             JObject::Null => "Null",
             JObject::Bool(_) => "Bool",
             JObject::Number(_) => "Number",
+            JObject::Float(_) => "Float",
             JObject::String(_) => "String",
+            JObject::Symbol(_) => "Symbol",
             JObject::List(_) => "List",
             JObject::Map(_) => "Map",
-            JObject::Func {
-                parameters: _,
-                definition: _,
-            } => "Func",
-            JObject::Macro {
-                parameters: _,
-                definition: _,
-            } => "Macro",
+            JObject::Func { .. } => "Func",
+            JObject::Macro { .. } => "Macro",
         };
         name.to_string()
     }
 }
 
+/// Compact, valid JSON. See `serialize` for the other forms.
 impl fmt::Display for JObject {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let str: String = match self {
-            JObject::Null => "[]".to_string(),
-            JObject::Bool(true) => "true".to_string(),
-            JObject::Bool(false) => "false".to_string(),
-            JObject::Number(n) => n.to_string(),
-            JObject::String(s) => ["\"", s, "\""].concat(),
-            JObject::List(l) => {
-                "[".to_owned()
-                    + &l.iter()
-                        .map(|x| format!("{}", x).to_string())
-                        .collect::<Vec<String>>()
-                        .join(",")
-                    + "]"
-            }
-            JObject::Map(m) => {
-                "{".to_owned()
-                    + &m.iter()
-                        .map(|(k, v)| format!("{}:{}", k, v))
-                        .collect::<Vec<String>>()
-                        .join(",")
-                    + "}"
-            }
-            JObject::Func {
-                parameters,
-                definition,
-            } => format!(r#"["f", [{}], {}]"#, parameters.join(","), definition),
-            JObject::Macro {
-                parameters,
-                definition,
-            } => format!(r#"["macro", [{}], {}]"#, parameters.join(","), definition),
-        };
-        write!(f, "{}", str)
+        f.write_str(&serialize::compact(self))
+    }
+}
+
+/// Formats a float so it reads back as a `Float`, e.g. `3.0` rather than `3`.
+pub fn format_float(n: f64) -> String {
+    let s = n.to_string();
+    if n.is_finite() && !s.contains(['.', 'e', 'E']) {
+        s + ".0"
+    } else {
+        s
+    }
+}
+
+/// The 1-based line and column, in characters, of byte `offset` in `text`.
+pub fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |n| n + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// Operands of a binary arithmetic operation after numeric promotion.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Numbers {
+    Ints(i64, i64),
+    Floats(f64, f64),
+}
+
+/// Promotes a pair of numbers to a common type: two `Number`s stay
+/// integers, and if either side is a `Float` both become floats.
+pub fn promote(a: &JObject, b: &JObject) -> Option<Numbers> {
+    match (a, b) {
+        (JObject::Number(a), JObject::Number(b)) => Some(Numbers::Ints(*a, *b)),
+        _ => Some(Numbers::Floats(a.as_f64()?, b.as_f64()?)),
+    }
+}
+
+/// Quotes `s` as a JSON string literal, escaping anything that needs it.
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
     }
+    out.push('"');
+    out
 }
 
 pub trait ToJObject {
@@ -297,6 +680,12 @@ impl ToJObject for i64 {
     }
 }
 
+impl ToJObject for f64 {
+    fn to_jobject(&self) -> JObject {
+        JObject::Float(*self)
+    }
+}
+
 impl ToJObject for &str {
     fn to_jobject(&self) -> JObject {
         JObject::String(self.to_string())
@@ -309,8 +698,248 @@ impl ToJObject for str {
     }
 }
 
-impl ToJObject for Vec<JObject> {
+impl ToJObject for f32 {
+    fn to_jobject(&self) -> JObject {
+        JObject::Float(*self as f64)
+    }
+}
+
+impl ToJObject for String {
     fn to_jobject(&self) -> JObject {
-        JObject::List(self.clone())
+        JObject::String(self.clone())
     }
 }
+
+impl<T: ToJObject> ToJObject for Vec<T> {
+    fn to_jobject(&self) -> JObject {
+        JObject::List(self.iter().map(|x| x.to_jobject()).collect())
+    }
+}
+
+impl<T: ToJObject> ToJObject for Option<T> {
+    /// `None` becomes `Null`.
+    fn to_jobject(&self) -> JObject {
+        match self {
+            Some(x) => x.to_jobject(),
+            None => JObject::Null,
+        }
+    }
+}
+
+impl<T: ToJObject> ToJObject for Box<T> {
+    fn to_jobject(&self) -> JObject {
+        (**self).to_jobject()
+    }
+}
+
+impl<T: ToJObject> ToJObject for OrderedMap<T> {
+    fn to_jobject(&self) -> JObject {
+        JObject::Map(
+            self.iter()
+                .map(|(k, v)| (k.clone(), Box::new(v.to_jobject())))
+                .collect(),
+        )
+    }
+}
+
+impl<T: ToJObject> ToJObject for HashMap<String, T> {
+    fn to_jobject(&self) -> JObject {
+        JObject::Map(
+            self.iter()
+                .map(|(k, v)| (k.clone(), Box::new(v.to_jobject())))
+                .collect(),
+        )
+    }
+}
+
+macro_rules! impl_tuple_to_jobject {
+    ($($t:ident $i:tt),*) => {
+        impl<$($t: ToJObject),*> ToJObject for ($($t,)*) {
+            /// Tuples become lists.
+            fn to_jobject(&self) -> JObject {
+                JObject::List(vec![$(self.$i.to_jobject()),*])
+            }
+        }
+    };
+}
+
+impl_tuple_to_jobject!(A 0);
+impl_tuple_to_jobject!(A 0, B 1);
+impl_tuple_to_jobject!(A 0, B 1, C 2);
+impl_tuple_to_jobject!(A 0, B 1, C 2, D 3);
+
+impl ToJObject for JObject {
+    fn to_jobject(&self) -> JObject {
+        self.clone()
+    }
+}
+
+/// The inverse of `ToJObject`: reads a Rust value out of a `JObject`.
+pub trait FromJObject: Sized {
+    /// What the value should look like, for error messages.
+    fn typename() -> String;
+
+    fn from_jobject(o: &JObject) -> Option<Self>;
+}
+
+impl FromJObject for JObject {
+    fn typename() -> String {
+        "Any".to_string()
+    }
+
+    fn from_jobject(o: &JObject) -> Option<Self> {
+        Some(o.clone())
+    }
+}
+
+impl FromJObject for bool {
+    fn typename() -> String {
+        "Bool".to_string()
+    }
+
+    fn from_jobject(o: &JObject) -> Option<Self> {
+        match o {
+            JObject::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+impl FromJObject for i64 {
+    fn typename() -> String {
+        "Number".to_string()
+    }
+
+    fn from_jobject(o: &JObject) -> Option<Self> {
+        match o {
+            JObject::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+impl FromJObject for f64 {
+    fn typename() -> String {
+        "Float".to_string()
+    }
+
+    /// Accepts a `Number` too, following the usual numeric promotion.
+    fn from_jobject(o: &JObject) -> Option<Self> {
+        o.as_f64()
+    }
+}
+
+impl FromJObject for String {
+    fn typename() -> String {
+        "String".to_string()
+    }
+
+    fn from_jobject(o: &JObject) -> Option<Self> {
+        match o {
+            JObject::String(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromJObject for f32 {
+    fn typename() -> String {
+        "Float".to_string()
+    }
+
+    fn from_jobject(o: &JObject) -> Option<Self> {
+        o.as_f64().map(|n| n as f32)
+    }
+}
+
+impl<T: FromJObject> FromJObject for Vec<T> {
+    fn typename() -> String {
+        "List".to_string()
+    }
+
+    fn from_jobject(o: &JObject) -> Option<Self> {
+        match o {
+            JObject::List(l) => l.iter().map(T::from_jobject).collect(),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromJObject> FromJObject for Option<T> {
+    fn typename() -> String {
+        format!("{} or Null", T::typename())
+    }
+
+    /// `Null` becomes `None`.
+    fn from_jobject(o: &JObject) -> Option<Self> {
+        match o {
+            JObject::Null => Some(None),
+            o => T::from_jobject(o).map(Some),
+        }
+    }
+}
+
+impl<T: FromJObject> FromJObject for Box<T> {
+    fn typename() -> String {
+        T::typename()
+    }
+
+    fn from_jobject(o: &JObject) -> Option<Self> {
+        T::from_jobject(o).map(Box::new)
+    }
+}
+
+impl<T: FromJObject> FromJObject for OrderedMap<T> {
+    fn typename() -> String {
+        "Map".to_string()
+    }
+
+    fn from_jobject(o: &JObject) -> Option<Self> {
+        match o {
+            JObject::Map(m) => m
+                .iter()
+                .map(|(k, v)| Some((k.clone(), T::from_jobject(v)?)))
+                .collect(),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromJObject> FromJObject for HashMap<String, T> {
+    fn typename() -> String {
+        "Map".to_string()
+    }
+
+    fn from_jobject(o: &JObject) -> Option<Self> {
+        match o {
+            JObject::Map(m) => m
+                .iter()
+                .map(|(k, v)| Some((k.clone(), T::from_jobject(v)?)))
+                .collect(),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! impl_tuple_from_jobject {
+    ($n:expr; $($t:ident $i:tt),*) => {
+        impl<$($t: FromJObject),*> FromJObject for ($($t,)*) {
+            fn typename() -> String {
+                format!("List of {}", $n)
+            }
+
+            /// Reads a list of exactly the tuple's length.
+            fn from_jobject(o: &JObject) -> Option<Self> {
+                match o {
+                    JObject::List(l) if l.len() == $n => Some(($($t::from_jobject(&l[$i])?,)*)),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+impl_tuple_from_jobject!(1; A 0);
+impl_tuple_from_jobject!(2; A 0, B 1);
+impl_tuple_from_jobject!(3; A 0, B 1, C 2);
+impl_tuple_from_jobject!(4; A 0, B 1, C 2, D 3);
@@ -1,5 +1,14 @@
+// Lets code generated by `jl-derive` name this crate from inside it too.
+extern crate self as jllib;
+
+pub mod args;
+pub mod error;
 pub mod eval;
 pub mod json;
+pub mod locate;
+pub mod map;
+pub mod regex;
+pub mod serialize;
 pub mod stdlib;
 
 #[cfg(test)]
@@ -0,0 +1,229 @@
+//! Finding where in a program an unknown name was used.
+//!
+//! Values don't remember where they were read from, and `eval` copies code
+//! into functions and tail calls as it goes, so errors can't carry a
+//! position. Instead, when strict mode reports an unknown name, `locate`
+//! walks the parsed program alongside its spans, following the forms that
+//! bind names, and reports the first use of the name that nothing binds.
+
+use crate::error::Error;
+use crate::eval::unbound_prefix;
+use crate::json::{self, JObject, ParseOptions, Span};
+
+/// Adds where `program` uses the name in an `unbound-symbol` or
+/// `unknown-function` error, e.g. `no function printn at line 3, column 6`.
+/// `program` and `spans` come from `json::try_parse_spanned(source, ...)`.
+///
+/// Other errors are returned unchanged, as are names that no part of
+/// `program` uses unbound, e.g. because it only held the name as data and
+/// a macro called it.
+pub fn locate(
+    err: Error,
+    program: &JObject,
+    spans: &Span,
+    source: &str,
+    options: ParseOptions,
+) -> Error {
+    let Error::Raised { kind, message } = &err else {
+        return err;
+    };
+    let function = match kind.as_str() {
+        "unknown-function" => true,
+        "unbound-symbol" => false,
+        _ => return err,
+    };
+    let Some(name) = message
+        .strip_prefix(unbound_prefix(kind))
+        .and_then(|rest| rest.strip_prefix(' '))
+    else {
+        return err;
+    };
+    let finder = Finder {
+        name,
+        function,
+        symbols: options.symbols,
+    };
+    match finder.code(program, spans, &mut Vec::new()) {
+        Some(offset) => {
+            let (line, column) = json::line_column(source, offset);
+            Error::new(
+                kind,
+                format!("{} at line {}, column {}", message, line, column),
+            )
+        }
+        None => err,
+    }
+}
+
+struct Finder<'a> {
+    name: &'a str,
+    /// Looking for a call of `name` rather than a variable.
+    function: bool,
+    symbols: bool,
+}
+
+impl Finder<'_> {
+    /// The offset of the first unbound use of the name in `o`, evaluated as
+    /// code with the local names in `bound`.
+    fn code<'o>(&self, o: &'o JObject, span: &Span, bound: &mut Vec<&'o str>) -> Option<usize> {
+        match o {
+            JObject::List(l) => self.call(l, &span.children, bound),
+            JObject::Map(m) => self.all(m.values().map(|v| &**v), &span.children, bound),
+            JObject::Symbol(s) | JObject::String(s) if self.is_variable(o, s, bound) => {
+                Some(span.offset)
+            }
+            _ => None,
+        }
+    }
+
+    fn is_variable(&self, o: &JObject, s: &str, bound: &[&str]) -> bool {
+        !self.function && s == self.name && self.can_be_variable(o) && !bound.contains(&s)
+    }
+
+    /// Whether `o`, named `s`, refers to a local binding.
+    fn is_local(&self, o: &JObject, s: &str, bound: &[&str]) -> bool {
+        self.can_be_variable(o) && bound.contains(&s)
+    }
+
+    /// With `$` symbols, plain strings are never variables.
+    fn can_be_variable(&self, o: &JObject) -> bool {
+        matches!(o, JObject::Symbol(_)) || !self.symbols
+    }
+
+    fn all<'o>(
+        &self,
+        forms: impl IntoIterator<Item = &'o JObject>,
+        spans: &[Span],
+        bound: &mut Vec<&'o str>,
+    ) -> Option<usize> {
+        std::iter::zip(forms, spans).find_map(|(o, span)| self.code(o, span, bound))
+    }
+
+    fn call<'o>(
+        &self,
+        l: &'o [JObject],
+        spans: &[Span],
+        bound: &mut Vec<&'o str>,
+    ) -> Option<usize> {
+        let head = l.first()?;
+        let args = &l[1..];
+        let arg_spans = spans.get(1..).unwrap_or_default();
+        let Some(h) = head.name().filter(|h| !self.is_local(head, h, bound)) else {
+            // A local function, or an expression that makes one.
+            return self.all(l, spans, bound);
+        };
+        // An unknown name at the head is a missing function, unless it's a
+        // `$` symbol, which is a missing variable.
+        if h == self.name && self.function == matches!(head, JObject::String(_)) {
+            return Some(spans[0].offset);
+        }
+        match (h, args) {
+            ("quote" | "import", _) => None,
+            ("quasiquote", [template]) => self.unquoted(template, &arg_spans[0], bound),
+            ("def", [_, value]) => self.code(value, &arg_spans[1], bound),
+            ("f" | "macro", [JObject::List(params), body]) => {
+                self.scoped(params.iter().filter_map(JObject::name), bound, |bound| {
+                    self.code(body, &arg_spans[1], bound)
+                })
+            }
+            ("let", [JObject::List(bindings), rest @ ..]) => {
+                let depth = bound.len();
+                let mut found = None;
+                for (binding, span) in std::iter::zip(bindings, &arg_spans[0].children) {
+                    if let Some((name, value, value_span)) = pair(binding, span) {
+                        found = found.or_else(|| self.code(value, value_span, bound));
+                        bound.push(name);
+                    }
+                }
+                let found = found.or_else(|| self.all(rest, &arg_spans[1..], bound));
+                bound.truncate(depth);
+                found
+            }
+            ("letrec", [JObject::List(bindings), rest @ ..]) => {
+                let pairs: Vec<_> = std::iter::zip(bindings, &arg_spans[0].children)
+                    .filter_map(|(binding, span)| pair(binding, span))
+                    .collect();
+                self.scoped(pairs.iter().map(|(name, ..)| *name), bound, |bound| {
+                    pairs
+                        .iter()
+                        .find_map(|(_, value, span)| self.code(value, span, bound))
+                        .or_else(|| self.all(rest, &arg_spans[1..], bound))
+                })
+            }
+            ("try", [body, clauses @ ..]) => self.code(body, &arg_spans[0], bound).or_else(|| {
+                std::iter::zip(clauses, &arg_spans[1..]).find_map(|(clause, span)| match clause {
+                    JObject::List(c) => match c.as_slice() {
+                        [_, name, handler] => self.scoped(name.name(), bound, |bound| {
+                            self.code(handler, &span.children[2], bound)
+                        }),
+                        [_, cleanup] => self.code(cleanup, &span.children[1], bound),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+            }),
+            // The values a `case` clause matches aren't evaluated.
+            ("case", [key, clauses @ ..]) => self.code(key, &arg_spans[0], bound).or_else(|| {
+                std::iter::zip(clauses, &arg_spans[1..]).find_map(|(clause, span)| match clause {
+                    JObject::List(c) if !c.is_empty() => {
+                        self.all(&c[1..], &span.children[1..], bound)
+                    }
+                    _ => None,
+                })
+            }),
+            _ => self.all(args, arg_spans, bound),
+        }
+    }
+
+    /// Like `code`, for the parts of a quasiquote template that are
+    /// evaluated: the template itself if it's an `unquote`, or any of its
+    /// elements that are a `splice-unquote`.
+    fn unquoted<'o>(&self, o: &'o JObject, span: &Span, bound: &mut Vec<&'o str>) -> Option<usize> {
+        let JObject::List(l) = o else {
+            return None;
+        };
+        if let Some(x) = marked("unquote", o) {
+            return self.code(x, &span.children[1], bound);
+        }
+        std::iter::zip(l, &span.children).find_map(|(element, span)| {
+            let x = marked("splice-unquote", element)?;
+            self.code(x, &span.children[1], bound)
+        })
+    }
+
+    /// Runs `f` with `names` bound as well.
+    fn scoped<'o>(
+        &self,
+        names: impl IntoIterator<Item = &'o str>,
+        bound: &mut Vec<&'o str>,
+        f: impl FnOnce(&mut Vec<&'o str>) -> Option<usize>,
+    ) -> Option<usize> {
+        let depth = bound.len();
+        bound.extend(names);
+        let found = f(bound);
+        bound.truncate(depth);
+        found
+    }
+}
+
+/// The name and value of a `let` or `letrec` binding, with the value's span.
+fn pair<'o, 's>(binding: &'o JObject, span: &'s Span) -> Option<(&'o str, &'o JObject, &'s Span)> {
+    match binding {
+        JObject::List(l) => match l.as_slice() {
+            [name, value] => Some((name.name()?, value, &span.children[1])),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// `x` if `o` is `[mark, x]`.
+fn marked<'o>(mark: &str, o: &'o JObject) -> Option<&'o JObject> {
+    match o {
+        JObject::List(l) => match l.as_slice() {
+            [JObject::String(s), x] if s == mark => Some(x),
+            _ => None,
+        },
+        _ => None,
+    }
+}
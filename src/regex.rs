@@ -0,0 +1,705 @@
+//! A small regular expression engine for `std::regex`.
+//!
+//! Supported syntax: literals, `.`, classes like `[a-z]` and `[^\d]`,
+//! `\d \w \s` and their negations, `^ $ \b \B`, groups `(...)`,
+//! non-capturing `(?:...)` and named `(?<name>...)` groups, `|`, and the
+//! quantifiers `* + ? {n} {n,} {n,m}`, each with a lazy `?` form.
+//!
+//! Patterns compile to a program for a Pike VM, which runs every way the
+//! pattern could match side by side instead of backtracking. Matching
+//! takes time proportional to the text times the program and never
+//! recurses, so no pattern can blow up on untrusted input. The work is
+//! reported through a `Charge` so it counts against the caller's limits.
+
+use crate::error::Error;
+
+/// How deeply groups and quantifiers may nest.
+const MAX_NESTING: usize = 100;
+
+/// How many instructions a compiled pattern may have. Counted repeats copy
+/// their body, so `(a{100}){100}` is already 10000.
+const MAX_PROGRAM: usize = 1 << 16;
+
+#[derive(Debug)]
+pub struct Regex {
+    prog: Vec<Inst>,
+    /// Number of capturing groups, not counting the whole match.
+    groups: usize,
+    /// Named groups and their slots.
+    names: Vec<(String, usize)>,
+}
+
+/// Where each group matched, as character ranges. Slot 0 is the whole
+/// match.
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+/// Called with units of work as matching goes along. An error stops the
+/// match and is passed on.
+pub type Charge<'a> = dyn FnMut(u64) -> Result<(), Error> + 'a;
+
+#[derive(Debug)]
+enum Node {
+    Char(char),
+    /// `.`, anything but a newline.
+    Any,
+    Class(Class),
+    Start,
+    End,
+    /// `\b` if true, `\B` if false.
+    WordBoundary(bool),
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Class {
+    items: Vec<ClassItem>,
+    negated: bool,
+}
+
+#[derive(Debug, Clone)]
+enum ClassItem {
+    Range(char, char),
+    /// `\d`, `\w` or `\s`, negated for the upper case forms.
+    Perl(char, bool),
+}
+
+impl Class {
+    fn matches(&self, c: char) -> bool {
+        let found = self.items.iter().any(|item| match *item {
+            ClassItem::Range(lo, hi) => lo <= c && c <= hi,
+            ClassItem::Perl(kind, negated) => perl(kind, c) != negated,
+        });
+        found != self.negated
+    }
+}
+
+fn perl(kind: char, c: char) -> bool {
+    match kind {
+        'd' => c.is_ascii_digit(),
+        'w' => is_word(c),
+        _ => c.is_whitespace(),
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+impl Regex {
+    /// Compiles `pattern`, failing with `bad-regex`.
+    pub fn new(pattern: &str) -> Result<Regex, Error> {
+        let mut parser = Parser {
+            pattern,
+            chars: pattern.chars().collect(),
+            i: 0,
+            depth: 0,
+            groups: 0,
+            names: Vec::new(),
+        };
+        let root = parser.alternation()?;
+        if parser.i < parser.chars.len() {
+            return Err(parser.error("unmatched ')'"));
+        }
+        let mut compiler = Compiler { prog: Vec::new() };
+        compiler.emit(Inst::Save(0))?;
+        compiler.node(&root)?;
+        compiler.emit(Inst::Save(1))?;
+        compiler.emit(Inst::Match)?;
+        Ok(Regex {
+            prog: compiler.prog,
+            groups: parser.groups,
+            names: parser.names,
+        })
+    }
+
+    pub fn groups(&self) -> usize {
+        self.groups
+    }
+
+    pub fn names(&self) -> &[(String, usize)] {
+        &self.names
+    }
+
+    /// Whether the whole of `text` matches.
+    pub fn is_full_match(&self, text: &[char], charge: &mut Charge) -> Result<bool, Error> {
+        Ok(self.run(text, 0, true, charge)?.is_some())
+    }
+
+    /// The first match starting at or after `start`.
+    pub fn find_at(
+        &self,
+        text: &[char],
+        start: usize,
+        charge: &mut Charge,
+    ) -> Result<Option<Captures>, Error> {
+        self.run(text, start, false, charge)
+    }
+
+    /// Every non-overlapping match, left to right. After an empty match the
+    /// search moves on a character, so it can't match there again.
+    pub fn find_all(&self, text: &[char], charge: &mut Charge) -> Result<Vec<Captures>, Error> {
+        let mut found = Vec::new();
+        let mut start = 0;
+        while start <= text.len() {
+            let Some(caps) = self.find_at(text, start, charge)? else {
+                break;
+            };
+            let (s, e) = caps[0].unwrap();
+            start = if e == s { e + 1 } else { e };
+            found.push(caps);
+        }
+        Ok(found)
+    }
+}
+
+struct Parser<'a> {
+    pattern: &'a str,
+    chars: Vec<char>,
+    i: usize,
+    /// Groups and quantifiers around the current position.
+    depth: usize,
+    groups: usize,
+    names: Vec<(String, usize)>,
+}
+
+impl Parser<'_> {
+    fn error(&self, what: &str) -> Error {
+        Error::new(
+            "bad-regex",
+            format!("{} at {} in {:?}", what, self.i, self.pattern),
+        )
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.i).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.i += 1;
+            return true;
+        }
+        false
+    }
+
+    fn alternation(&mut self) -> Result<Node, Error> {
+        let mut alts = vec![self.concat()?];
+        while self.eat('|') {
+            alts.push(self.concat()?);
+        }
+        Ok(if alts.len() == 1 {
+            alts.pop().unwrap()
+        } else {
+            Node::Alt(alts)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, Error> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    /// Runs `parse` one level deeper, so nothing recurses without bound.
+    fn nested<T>(&mut self, parse: fn(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        if self.depth == MAX_NESTING {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        let done = parse(self);
+        self.depth -= 1;
+        done
+    }
+
+    fn quantified(&mut self, mut node: Node) -> Result<Node, Error> {
+        // Each quantifier wraps the last, so they count towards the nesting.
+        let mut depth = self.depth;
+        loop {
+            let start = self.i;
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => match self.counts() {
+                    Some(counts) => counts,
+                    // Not a valid count, so it's a literal brace.
+                    None => return Ok(node),
+                },
+                _ => return Ok(node),
+            };
+            if self.i == start {
+                self.i += 1;
+            }
+            if matches!(node, Node::Start | Node::End | Node::WordBoundary(_)) {
+                self.i = start;
+                return Err(self.error("nothing to repeat"));
+            }
+            if max.is_some_and(|max| max < min) {
+                return Err(self.error("bad repeat range"));
+            }
+            if depth == MAX_NESTING {
+                return Err(self.error("nested too deeply"));
+            }
+            depth += 1;
+            let greedy = !self.eat('?');
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+                greedy,
+            };
+        }
+    }
+
+    /// Reads `{n}`, `{n,}` or `{n,m}`, leaving the position alone if it
+    /// isn't one.
+    fn counts(&mut self) -> Option<(usize, Option<usize>)> {
+        let start = self.i;
+        self.i += 1;
+        let counts = (|| {
+            let min = self.number()?;
+            if self.eat('}') {
+                return Some((min, Some(min)));
+            }
+            if !self.eat(',') {
+                return None;
+            }
+            if self.eat('}') {
+                return Some((min, None));
+            }
+            let max = self.number()?;
+            self.eat('}').then_some((min, Some(max)))
+        })();
+        if counts.is_none() {
+            self.i = start;
+        }
+        counts
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.i;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.i += 1;
+        }
+        let digits: String = self.chars[start..self.i].iter().collect();
+        digits.parse().ok()
+    }
+
+    fn atom(&mut self) -> Result<Node, Error> {
+        let c = self.peek().unwrap();
+        self.i += 1;
+        match c {
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '(' => self.group(),
+            '[' => self.class().map(Node::Class),
+            '\\' => self.escape(),
+            '*' | '+' | '?' => {
+                self.i -= 1;
+                Err(self.error("nothing to repeat"))
+            }
+            c => Ok(Node::Char(c)),
+        }
+    }
+
+    fn group(&mut self) -> Result<Node, Error> {
+        let slot = if self.eat('?') {
+            if self.eat(':') {
+                None
+            } else if self.eat('<') || (self.eat('P') && self.eat('<')) {
+                let start = self.i;
+                while self.peek().is_some_and(is_word) {
+                    self.i += 1;
+                }
+                let name: String = self.chars[start..self.i].iter().collect();
+                if name.is_empty() || !self.eat('>') {
+                    return Err(self.error("bad group name"));
+                }
+                if self.names.iter().any(|(n, _)| *n == name) {
+                    return Err(self.error("duplicate group name"));
+                }
+                self.groups += 1;
+                self.names.push((name, self.groups));
+                Some(self.groups)
+            } else {
+                return Err(self.error("unknown group flag"));
+            }
+        } else {
+            self.groups += 1;
+            Some(self.groups)
+        };
+        let inner = self.nested(Parser::alternation)?;
+        if !self.eat(')') {
+            return Err(self.error("missing ')'"));
+        }
+        Ok(Node::Group(Box::new(inner), slot))
+    }
+
+    fn escape(&mut self) -> Result<Node, Error> {
+        let Some(c) = self.peek() else {
+            return Err(self.error("trailing '\\'"));
+        };
+        self.i += 1;
+        Ok(match c {
+            'b' => Node::WordBoundary(true),
+            'B' => Node::WordBoundary(false),
+            'd' | 'w' | 's' | 'D' | 'W' | 'S' => Node::Class(Class {
+                items: vec![perl_item(c)],
+                negated: false,
+            }),
+            c => Node::Char(self.escaped_char(c)?),
+        })
+    }
+
+    /// The character an escape like `\n` or `\.` stands for.
+    fn escaped_char(&self, c: char) -> Result<char, Error> {
+        match c {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            c if c.is_ascii_alphanumeric() => Err(self.error("unknown escape")),
+            c => Ok(c),
+        }
+    }
+
+    fn class(&mut self) -> Result<Class, Error> {
+        let negated = self.eat('^');
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("missing ']'"));
+            };
+            self.i += 1;
+            if c == ']' && !first {
+                return Ok(Class { items, negated });
+            }
+            first = false;
+            let lo = match c {
+                '\\' => {
+                    let Some(e) = self.peek() else {
+                        return Err(self.error("missing ']'"));
+                    };
+                    self.i += 1;
+                    if "dwsDWS".contains(e) {
+                        items.push(perl_item(e));
+                        continue;
+                    }
+                    self.escaped_char(e)?
+                }
+                c => c,
+            };
+            // A '-' at either end of the class is literal.
+            if self.peek() == Some('-') && self.chars.get(self.i + 1).is_some_and(|&c| c != ']') {
+                self.i += 1;
+                let hi = match self.peek() {
+                    Some('\\') => {
+                        self.i += 1;
+                        let e = self.peek().ok_or_else(|| self.error("missing ']'"))?;
+                        self.i += 1;
+                        self.escaped_char(e)?
+                    }
+                    Some(c) => {
+                        self.i += 1;
+                        c
+                    }
+                    None => return Err(self.error("missing ']'")),
+                };
+                if hi < lo {
+                    return Err(self.error("bad class range"));
+                }
+                items.push(ClassItem::Range(lo, hi));
+            } else {
+                items.push(ClassItem::Range(lo, lo));
+            }
+        }
+    }
+}
+
+fn perl_item(c: char) -> ClassItem {
+    ClassItem::Perl(c.to_ascii_lowercase(), c.is_ascii_uppercase())
+}
+
+/// One step of a compiled pattern.
+#[derive(Debug)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    /// `^`, `$`, `\b` or `\B`, which match without moving.
+    Assert(Look),
+    /// Records the current position in a capture slot. Group `n` starts in
+    /// slot `2n` and ends in `2n + 1`.
+    Save(usize),
+    /// Tries both, preferring the first.
+    Split(usize, usize),
+    Jmp(usize),
+    Match,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Look {
+    Start,
+    End,
+    WordBoundary(bool),
+}
+
+struct Compiler {
+    prog: Vec<Inst>,
+}
+
+impl Compiler {
+    fn emit(&mut self, inst: Inst) -> Result<usize, Error> {
+        if self.prog.len() == MAX_PROGRAM {
+            return Err(Error::new(
+                "bad-regex",
+                format!("pattern compiles to more than {} steps", MAX_PROGRAM),
+            ));
+        }
+        self.prog.push(inst);
+        Ok(self.prog.len() - 1)
+    }
+
+    /// Leaves room for a jump whose target isn't known yet.
+    fn hole(&mut self) -> Result<usize, Error> {
+        self.emit(Inst::Jmp(usize::MAX))
+    }
+
+    /// Fills the hole at `at` with a split to `first` and then `second`.
+    fn patch(&mut self, at: usize, first: usize, second: usize) {
+        self.prog[at] = Inst::Split(first, second);
+    }
+
+    fn node(&mut self, node: &Node) -> Result<(), Error> {
+        match node {
+            Node::Char(c) => self.emit(Inst::Char(*c)).map(drop),
+            Node::Any => self.emit(Inst::Any).map(drop),
+            Node::Class(class) => self.emit(Inst::Class(class.clone())).map(drop),
+            Node::Start => self.emit(Inst::Assert(Look::Start)).map(drop),
+            Node::End => self.emit(Inst::Assert(Look::End)).map(drop),
+            Node::WordBoundary(want) => {
+                self.emit(Inst::Assert(Look::WordBoundary(*want))).map(drop)
+            }
+            Node::Group(inner, None) => self.node(inner),
+            Node::Group(inner, Some(slot)) => {
+                self.emit(Inst::Save(2 * slot))?;
+                self.node(inner)?;
+                self.emit(Inst::Save(2 * slot + 1)).map(drop)
+            }
+            Node::Concat(nodes) => nodes.iter().try_for_each(|n| self.node(n)),
+            Node::Alt(alts) => {
+                let mut jumps = Vec::new();
+                let (last, rest) = alts.split_last().unwrap();
+                for alt in rest {
+                    let split = self.hole()?;
+                    self.node(alt)?;
+                    jumps.push(self.hole()?);
+                    let next = self.prog.len();
+                    self.patch(split, split + 1, next);
+                }
+                self.node(last)?;
+                let end = self.prog.len();
+                for jump in jumps {
+                    self.prog[jump] = Inst::Jmp(end);
+                }
+                Ok(())
+            }
+            Node::Repeat {
+                node,
+                min,
+                max,
+                greedy,
+            } => {
+                for _ in 0..*min {
+                    self.node(node)?;
+                }
+                // The holes become splits between another go at `node` and
+                // leaving, in the order `greedy` asks for.
+                let order = |body: usize, out: usize| {
+                    if *greedy {
+                        (body, out)
+                    } else {
+                        (out, body)
+                    }
+                };
+                match max {
+                    None => {
+                        let split = self.hole()?;
+                        self.node(node)?;
+                        self.emit(Inst::Jmp(split))?;
+                        let (first, second) = order(split + 1, self.prog.len());
+                        self.patch(split, first, second);
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.hole()?);
+                            self.node(node)?;
+                        }
+                        let end = self.prog.len();
+                        for split in splits {
+                            let (first, second) = order(split + 1, end);
+                            self.patch(split, first, second);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The threads alive at one position: where each is in the program, and
+/// its capture slots, highest priority first.
+struct Threads {
+    list: Vec<(usize, Vec<Option<usize>>)>,
+    /// The generation in which each instruction was last reached, so each
+    /// is taken at most once per position.
+    seen: Vec<u64>,
+    generation: u64,
+}
+
+impl Threads {
+    fn new(len: usize) -> Threads {
+        Threads {
+            list: Vec::new(),
+            seen: vec![0; len],
+            generation: 1,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.list.clear();
+        self.generation += 1;
+    }
+}
+
+/// How much work to report to a `Charge` at once.
+const CHARGE_EVERY: u64 = 1024;
+
+impl Regex {
+    /// Runs the program from `start`. A search also starts a thread at each
+    /// later position until something matches; a full match only starts at
+    /// `start` and only accepts a match at the end of the text.
+    fn run(
+        &self,
+        text: &[char],
+        start: usize,
+        full: bool,
+        charge: &mut Charge,
+    ) -> Result<Option<Captures>, Error> {
+        let slots = 2 * (self.groups + 1);
+        let mut current = Threads::new(self.prog.len());
+        let mut next = Threads::new(self.prog.len());
+        let mut matched = None;
+        let mut work = 0;
+        let mut i = start;
+        loop {
+            if matched.is_none() && (!full || i == start) {
+                self.add(&mut current, 0, vec![None; slots], text, i);
+            }
+            if current.list.is_empty() {
+                break;
+            }
+            work += current.list.len() as u64;
+            if work >= CHARGE_EVERY {
+                charge(std::mem::take(&mut work))?;
+            }
+            for (pc, caps) in current.list.drain(..) {
+                let accepts = match &self.prog[pc] {
+                    Inst::Match => {
+                        if full && i < text.len() {
+                            continue;
+                        }
+                        // Anything after this thread has lower priority.
+                        matched = Some(caps);
+                        break;
+                    }
+                    Inst::Char(c) => text.get(i) == Some(c),
+                    Inst::Any => text.get(i).is_some_and(|&c| c != '\n'),
+                    Inst::Class(class) => text.get(i).is_some_and(|&c| class.matches(c)),
+                    _ => unreachable!("only consuming steps and Match are queued"),
+                };
+                if accepts {
+                    self.add(&mut next, pc + 1, caps, text, i + 1);
+                }
+            }
+            if i == text.len() {
+                break;
+            }
+            std::mem::swap(&mut current, &mut next);
+            next.clear();
+            i += 1;
+        }
+        charge(work)?;
+        Ok(matched.map(|slots: Vec<Option<usize>>| {
+            slots.chunks(2).map(|pair| pair[0].zip(pair[1])).collect()
+        }))
+    }
+
+    /// Follows `pc` through the steps that don't consume a character,
+    /// queueing a thread at each one that does. Uses its own stack rather
+    /// than recursing, and visits in priority order.
+    fn add(
+        &self,
+        threads: &mut Threads,
+        pc: usize,
+        caps: Vec<Option<usize>>,
+        text: &[char],
+        i: usize,
+    ) {
+        let mut stack = vec![(pc, caps)];
+        while let Some((pc, mut caps)) = stack.pop() {
+            if threads.seen[pc] == threads.generation {
+                continue;
+            }
+            threads.seen[pc] = threads.generation;
+            match &self.prog[pc] {
+                Inst::Jmp(to) => stack.push((*to, caps)),
+                Inst::Split(first, second) => {
+                    stack.push((*second, caps.clone()));
+                    stack.push((*first, caps));
+                }
+                Inst::Save(slot) => {
+                    caps[*slot] = Some(i);
+                    stack.push((pc + 1, caps));
+                }
+                Inst::Assert(look) => {
+                    if look.holds(text, i) {
+                        stack.push((pc + 1, caps));
+                    }
+                }
+                _ => threads.list.push((pc, caps)),
+            }
+        }
+    }
+}
+
+impl Look {
+    fn holds(self, text: &[char], i: usize) -> bool {
+        match self {
+            Look::Start => i == 0,
+            Look::End => i == text.len(),
+            Look::WordBoundary(want) => {
+                let before = i > 0 && is_word(text[i - 1]);
+                let after = text.get(i).is_some_and(|&c| is_word(c));
+                (before != after) == want
+            }
+        }
+    }
+}
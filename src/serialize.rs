@@ -0,0 +1,282 @@
+//! Writing values out as JSON text.
+//!
+//! There are three forms. `compact` is a single line and is what `Display`
+//! prints. `pretty` indents anything that doesn't fit in a given width.
+//! `canonical` follows RFC 8785: keys are sorted and numbers normalized, so
+//! equal values always serialize to the same bytes, for hashing and signing.
+//!
+//! Functions and macros are written as the forms that define them, e.g.
+//! `["f",["x"],"x"]`, and non-finite floats as `null`, so every output is
+//! valid JSON.
+
+use std::borrow::Cow;
+
+use crate::json::{escape, format_float, JObject, ParseOptions};
+
+/// Single-line JSON with no extra whitespace.
+pub fn compact(o: &JObject) -> String {
+    compact_with(o, ParseOptions::default())
+}
+
+/// `compact` for text that will be read back with `options`. With
+/// `symbols`, a string starting with `$` gets a second one, so it doesn't
+/// come back as a symbol.
+pub fn compact_with(o: &JObject, options: ParseOptions) -> String {
+    let mut out = String::new();
+    write_flat(&mut out, o, Style::Compact, options);
+    out
+}
+
+/// Indented JSON. Lists and maps that fit within `width` columns stay on
+/// one line, anything longer puts each element on its own line.
+pub fn pretty(o: &JObject, width: usize) -> String {
+    let mut out = String::new();
+    write_pretty(&mut out, &Layout::new(o), 0, 0, width);
+    out
+}
+
+/// RFC 8785 canonical JSON: no whitespace, keys sorted by UTF-16 code
+/// units, and numbers written the way JavaScript does, so `3.0` and `3` are
+/// both `3`.
+pub fn canonical(o: &JObject) -> String {
+    let mut out = String::new();
+    write_flat(&mut out, o, Style::Canonical, ParseOptions::default());
+    out
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Style {
+    Compact,
+    /// Compact with a space after `,` and `:`, for one-line parts of
+    /// pretty output.
+    Spaced,
+    Canonical,
+}
+
+/// Functions and macros as the plain lists that would define them.
+fn as_data(o: &JObject) -> Cow<'_, JObject> {
+    let (head, parameters, definition) = match o {
+        JObject::Func {
+            parameters,
+            definition,
+            ..
+        } => ("f", parameters, definition),
+        JObject::Macro {
+            parameters,
+            definition,
+            ..
+        } => ("macro", parameters, definition),
+        _ => return Cow::Borrowed(o),
+    };
+    Cow::Owned(JObject::List(vec![
+        JObject::String(head.to_string()),
+        JObject::List(
+            parameters
+                .iter()
+                .map(|p| JObject::String(p.clone()))
+                .collect(),
+        ),
+        (**definition).clone(),
+    ]))
+}
+
+fn write_flat(out: &mut String, o: &JObject, style: Style, options: ParseOptions) {
+    let (comma, colon) = match style {
+        Style::Spaced => (", ", ": "),
+        _ => (",", ":"),
+    };
+    match &*as_data(o) {
+        JObject::Null => out.push_str("null"),
+        JObject::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        // JSON numbers are doubles to RFC 8785, so integers too big for one
+        // are written as the double they'd be read as.
+        JObject::Number(n) if style == Style::Canonical && n.unsigned_abs() > 1 << 53 => {
+            out.push_str(&canonical_float(*n as f64))
+        }
+        JObject::Number(n) => out.push_str(&n.to_string()),
+        // JSON has no infinities or NaN. Only the host can make them, and
+        // they're written as `null`, like JavaScript's `JSON.stringify`.
+        JObject::Float(n) if !n.is_finite() => out.push_str("null"),
+        JObject::Float(n) if style == Style::Canonical => out.push_str(&canonical_float(*n)),
+        JObject::Float(n) => out.push_str(&format_float(*n)),
+        JObject::String(s) if options.symbols && s.starts_with('$') => {
+            out.push_str(&escape(&format!("${}", s)))
+        }
+        JObject::String(s) => out.push_str(&escape(s)),
+        JObject::Symbol(s) => out.push_str(&escape(&format!("${}", s))),
+        JObject::List(l) => {
+            out.push('[');
+            for (i, x) in l.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(comma);
+                }
+                write_flat(out, x, style, options);
+            }
+            out.push(']');
+        }
+        JObject::Map(m) => {
+            let mut entries: Vec<_> = m.iter().collect();
+            if style == Style::Canonical {
+                entries.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            }
+            out.push('{');
+            for (i, (k, v)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push_str(comma);
+                }
+                out.push_str(&escape(k));
+                out.push_str(colon);
+                write_flat(out, v, style, options);
+            }
+            out.push('}');
+        }
+        JObject::Func { .. } | JObject::Macro { .. } => unreachable!("as_data made a list"),
+    }
+}
+
+/// A value laid out for `pretty`, knowing how wide it is on one line so
+/// that's only worked out once.
+enum Layout {
+    Atom(String, usize),
+    List(Vec<Layout>, usize),
+    Map(Vec<(String, Layout)>, usize),
+}
+
+impl Layout {
+    fn new(o: &JObject) -> Layout {
+        // Elements are separated by `, ` and wrapped in brackets.
+        let joined = |widths: usize, n: usize| 2 + widths + 2 * n.saturating_sub(1);
+        match &*as_data(o) {
+            JObject::List(l) => {
+                let items: Vec<Layout> = l.iter().map(Layout::new).collect();
+                let width = joined(items.iter().map(Layout::width).sum(), items.len());
+                Layout::List(items, width)
+            }
+            JObject::Map(m) => {
+                let entries: Vec<(String, Layout)> =
+                    m.iter().map(|(k, v)| (escape(k), Layout::new(v))).collect();
+                let widths = entries
+                    .iter()
+                    .map(|(k, v)| k.chars().count() + 2 + v.width())
+                    .sum();
+                Layout::Map(entries, joined(widths, m.len()))
+            }
+            o => {
+                let mut flat = String::new();
+                write_flat(&mut flat, o, Style::Spaced, ParseOptions::default());
+                let width = flat.chars().count();
+                Layout::Atom(flat, width)
+            }
+        }
+    }
+
+    fn width(&self) -> usize {
+        match self {
+            Layout::Atom(_, width) | Layout::List(_, width) | Layout::Map(_, width) => *width,
+        }
+    }
+
+    /// Writes it on one line, like `Style::Spaced`.
+    fn write_flat(&self, out: &mut String) {
+        match self {
+            Layout::Atom(flat, _) => out.push_str(flat),
+            Layout::List(items, _) => {
+                out.push('[');
+                for (i, x) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    x.write_flat(out);
+                }
+                out.push(']');
+            }
+            Layout::Map(entries, _) => {
+                out.push('{');
+                for (i, (k, v)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    out.push_str(k);
+                    out.push_str(": ");
+                    v.write_flat(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// `column` is where `o` starts on the current line, `indent` is where the
+/// line holding `o` started.
+fn write_pretty(out: &mut String, o: &Layout, indent: usize, column: usize, width: usize) {
+    let fits = column + o.width() <= width;
+    match o {
+        Layout::List(items, _) if !fits && !items.is_empty() => {
+            out.push_str("[\n");
+            for (i, x) in items.iter().enumerate() {
+                push_indent(out, indent + 2);
+                write_pretty(out, x, indent + 2, indent + 2, width);
+                out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+            }
+            push_indent(out, indent);
+            out.push(']');
+        }
+        Layout::Map(entries, _) if !fits && !entries.is_empty() => {
+            out.push_str("{\n");
+            for (i, (k, v)) in entries.iter().enumerate() {
+                push_indent(out, indent + 2);
+                out.push_str(k);
+                out.push_str(": ");
+                let column = indent + 2 + k.chars().count() + 2;
+                write_pretty(out, v, indent + 2, column, width);
+                out.push_str(if i + 1 < entries.len() { ",\n" } else { "\n" });
+            }
+            push_indent(out, indent);
+            out.push('}');
+        }
+        o => o.write_flat(out),
+    }
+}
+
+fn push_indent(out: &mut String, n: usize) {
+    out.extend(std::iter::repeat_n(' ', n));
+}
+
+/// Formats a float like JavaScript's `Number.prototype.toString`, which
+/// RFC 8785 uses for all numbers.
+fn canonical_float(n: f64) -> String {
+    if n == 0.0 {
+        // Covers -0 too.
+        return "0".to_string();
+    }
+    // `{:e}` gives the shortest digits that round-trip, e.g. `1.25e-7`.
+    let sci = format!("{:e}", n.abs());
+    let (mantissa, exponent) = sci.split_once('e').unwrap();
+    let digits = mantissa.replace('.', "");
+    let k = digits.len() as i32;
+    // The decimal point goes after the first `point` digits.
+    let point = exponent.parse::<i32>().unwrap() + 1;
+
+    let body = if k <= point && point <= 21 {
+        digits + &"0".repeat((point - k) as usize)
+    } else if 0 < point && point <= 21 {
+        let (int, frac) = digits.split_at(point as usize);
+        format!("{}.{}", int, frac)
+    } else if -6 < point && point <= 0 {
+        format!("0.{}{}", "0".repeat(-point as usize), digits)
+    } else {
+        let e = point - 1;
+        let (first, rest) = digits.split_at(1);
+        let mantissa = if rest.is_empty() {
+            first.to_string()
+        } else {
+            format!("{}.{}", first, rest)
+        };
+        format!("{}e{}{}", mantissa, if e < 0 { "-" } else { "+" }, e.abs())
+    };
+    if n < 0.0 {
+        format!("-{}", body)
+    } else {
+        body
+    }
+}
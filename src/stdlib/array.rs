@@ -1,69 +1,362 @@
+use std::cmp::Ordering;
+
 use crate::{
-    eval::Environment,
-    json::{new_list, JObject},
+    args::{convert, Callable},
+    error::Error,
+    eval::{eval, Environment},
+    json::JObject,
+    map::OrderedMap,
 };
 
-use crate::eval::eval;
+use super::math::ordering;
+use super::truthy;
 
+// Functions that take a callback take it first, like `map`.
 pub fn load_mod(env: &mut Environment) {
-    env.insert_builtin("head", |env, args| {
-        if args.len() != 1 {
-            println!("bar arity");
-            return new_list(&["error", "bad-arity", &format!("{} != {}", args.len(), 1)]);
-        }
+    env.insert_typed("head", |_env, (vec,): (Vec<JObject>,)| {
+        Ok(vec.first().unwrap_or(&JObject::Null).clone())
+    });
+
+    env.insert_typed("tail", |_env, (vec,): (Vec<JObject>,)| {
+        Ok(vec.get(1..).unwrap_or_default().to_vec())
+    });
+
+    env.insert_typed("len", |_env, (vec,): (Vec<JObject>,)| Ok(vec.len() as i64));
 
-        match eval(env, &args[0]) {
-            JObject::List(vec) => vec.first().unwrap_or(&JObject::Null).clone(),
-            _ => JObject::List(Vec::new()),
+    // (map fn array)
+    env.insert_typed("map", |env, (func, array): (Callable, Vec<JObject>)| {
+        let mut done = Vec::new();
+        for element in array {
+            done.push(func.call(env, vec![element])?)
         }
+        Ok(done)
     });
 
-    env.insert_builtin("tail", |env, args| {
-        if args.len() != 1 {
-            println!("bar arity");
-            return new_list(&["error", "bad-arity", &format!("{} != {}", args.len(), 1)]);
+    env.insert_typed("filter", |env, (func, array): (Callable, Vec<JObject>)| {
+        let mut done = Vec::new();
+        for element in array {
+            if truthy(&func.call(env, vec![element.clone()])?) {
+                done.push(element);
+            }
         }
+        Ok(done)
+    });
+
+    // (reduce fn array) folds from the first element, or is null if empty
+    env.insert_typed("reduce", |env, (func, array): (Callable, Vec<JObject>)| {
+        let mut elements = array.into_iter();
+        let Some(first) = elements.next() else {
+            return Ok(JObject::Null);
+        };
+        elements.try_fold(first, |acc, x| func.call(env, vec![acc, x]))
+    });
+
+    // (fold fn init array)
+    env.insert_typed(
+        "fold",
+        |env, (func, init, array): (Callable, JObject, Vec<JObject>)| {
+            array
+                .into_iter()
+                .try_fold(init, |acc, x| func.call(env, vec![acc, x]))
+        },
+    );
 
-        match eval(env, &args[0]) {
-            JObject::List(vec) => JObject::List(vec[1..].to_vec()),
-            _ => JObject::List(Vec::new()),
+    // (range end), (range start end) or (range start end step)
+    env.insert_builtin("range", |env, args| {
+        let mut bounds = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            bounds.push(convert::<i64>("range", i, &eval(env, arg)?)?);
         }
+        let (start, end, step) = match bounds[..] {
+            [end] => (0, end, 1),
+            [start, end] => (start, end, 1),
+            [start, end, step] => (start, end, step),
+            _ => {
+                return Err(Error::new(
+                    "bad-arity",
+                    format!("{} not in 1..=3", args.len()),
+                ))
+            }
+        };
+        if step == 0 {
+            return Err(Error::new("bad-range", "step can't be 0"));
+        }
+        let (start, end, step) = (start as i128, end as i128, step as i128);
+        let len = match (end - start) / step {
+            _ if (end - start).signum() != step.signum() => 0,
+            whole if (end - start) % step == 0 => whole,
+            whole => whole + 1,
+        };
+        env.allocate(usize::try_from(len).unwrap_or(usize::MAX))?;
+        Ok(JObject::List(
+            (0..len)
+                .map(|i| JObject::Number((start + i * step) as i64))
+                .collect(),
+        ))
     });
 
-    env.insert_builtin("len", |env, args| {
-        if args.len() != 1 {
-            println!("bar arity");
-            return new_list(&["error", "bad-arity", &format!("{} != {}", args.len(), 1)]);
+    env.insert_builtin("concat", concat);
+
+    env.insert_typed("append", |env, (mut array, x): (Vec<JObject>, JObject)| {
+        env.allocate(array.len() + 1)?;
+        array.push(x);
+        Ok(array)
+    });
+
+    // (nth array i) is null when i is out of range
+    env.insert_typed("nth", |_env, (array, i): (Vec<JObject>, i64)| {
+        let element = usize::try_from(i).ok().and_then(|i| array.get(i));
+        Ok(element.cloned().unwrap_or(JObject::Null))
+    });
+
+    // (slice array start end), clamped to the array
+    env.insert_typed(
+        "slice",
+        |_env, (array, start, end): (Vec<JObject>, i64, i64)| {
+            let start = clamp(start, array.len());
+            let end = clamp(end, array.len()).max(start);
+            Ok(array[start..end].to_vec())
+        },
+    );
+
+    env.insert_typed("reverse", |_env, (mut array,): (Vec<JObject>,)| {
+        array.reverse();
+        Ok(array)
+    });
+
+    // (zip a b) pairs elements up, stopping at the shorter array
+    env.insert_typed("zip", |_env, (a, b): (Vec<JObject>, Vec<JObject>)| {
+        Ok(std::iter::zip(a, b)
+            .map(|(x, y)| JObject::List(vec![x, y]))
+            .collect::<Vec<_>>())
+    });
+
+    // (enumerate array) is [[0, a], [1, b], ...]
+    env.insert_typed("enumerate", |_env, (array,): (Vec<JObject>,)| {
+        Ok(array
+            .into_iter()
+            .enumerate()
+            .map(|(i, x)| JObject::List(vec![JObject::Number(i as i64), x]))
+            .collect::<Vec<_>>())
+    });
+
+    // (find fn array) is the first element fn accepts, or null
+    env.insert_typed("find", |env, (func, array): (Callable, Vec<JObject>)| {
+        for element in array {
+            if truthy(&func.call(env, vec![element.clone()])?) {
+                return Ok(element);
+            }
         }
+        Ok(JObject::Null)
+    });
 
-        match eval(env, &args[0]) {
-            JObject::List(vec) => JObject::Number(vec.len() as i64),
-            _ => JObject::Number(1),
+    env.insert_typed("any", |env, (func, array): (Callable, Vec<JObject>)| {
+        for element in array {
+            if truthy(&func.call(env, vec![element])?) {
+                return Ok(true);
+            }
         }
+        Ok(false)
     });
 
-    // (map fn array)
-    env.insert_builtin("map", |env, args| {
-        if args.len() != 2 {
-            println!("bar arity");
-            return new_list(&["error", "bad-arity", &format!("{} != {}", args.len(), 2)]);
+    env.insert_typed("all", |env, (func, array): (Callable, Vec<JObject>)| {
+        for element in array {
+            if !truthy(&func.call(env, vec![element])?) {
+                return Ok(false);
+            }
         }
+        Ok(true)
+    });
+
+    // (flatten array) removes one level of nesting
+    env.insert_typed("flatten", |env, (array,): (Vec<JObject>,)| {
+        flatten(env, array)
+    });
+
+    env.insert_typed(
+        "flat-map",
+        |env, (func, array): (Callable, Vec<JObject>)| {
+            let mut mapped = Vec::new();
+            for element in array {
+                mapped.push(func.call(env, vec![element])?);
+            }
+            flatten(env, mapped)
+        },
+    );
+
+    // (take n array) and (drop n array)
+    env.insert_typed("take", |_env, (n, array): (i64, Vec<JObject>)| {
+        Ok(array[..clamp(n, array.len())].to_vec())
+    });
+
+    env.insert_typed("drop", |_env, (n, array): (i64, Vec<JObject>)| {
+        Ok(array[clamp(n, array.len())..].to_vec())
+    });
+
+    // (group-by fn array) maps each key fn returns to the elements that gave
+    // it. Keys that aren't strings are written as JSON.
+    env.insert_typed(
+        "group-by",
+        |env, (func, array): (Callable, Vec<JObject>)| {
+            let mut groups: OrderedMap<Vec<JObject>> = OrderedMap::new();
+            for element in array {
+                let key = match func.call(env, vec![element.clone()])? {
+                    JObject::String(s) => s,
+                    key => key.to_string(),
+                };
+                match groups.get_mut(&key) {
+                    Some(group) => group.push(element),
+                    None => {
+                        groups.insert(key, vec![element]);
+                    }
+                }
+            }
+            Ok(groups)
+        },
+    );
 
-        let func = eval(env, &args[0]);
-        let array = eval(env, &args[1]);
-
-        match (func, array) {
-            (func, JObject::List(array)) => {
-                let mut done = Vec::new();
-                for element in array {
-                    // let funcall = JObject::List(vec![func.clone(), element.clone()]);
-                    // let res = eval(env, &funcall);
-                    let res = eval(env, &JObject::List(vec![func.clone(), element.clone()]));
-                    done.push(res)
+    // (partition fn array) is [accepted, rejected]
+    env.insert_typed(
+        "partition",
+        |env, (func, array): (Callable, Vec<JObject>)| {
+            let (mut yes, mut no) = (Vec::new(), Vec::new());
+            for element in array {
+                if truthy(&func.call(env, vec![element.clone()])?) {
+                    yes.push(element);
+                } else {
+                    no.push(element);
                 }
-                JObject::List(done)
             }
-            _ => JObject::Null,
+            Ok((yes, no))
+        },
+    );
+
+    // (distinct array) keeps the first of each group of `==` elements
+    env.insert_typed("distinct", |_env, (array,): (Vec<JObject>,)| {
+        let mut done: Vec<JObject> = Vec::new();
+        for element in array {
+            if !done.contains(&element) {
+                done.push(element);
+            }
         }
+        Ok(done)
+    });
+
+    // (sort array) orders numbers or strings, ascending
+    env.insert_typed("sort", |_env, (array,): (Vec<JObject>,)| {
+        merge_sort(
+            array,
+            &mut |a, b| Ok(natural_order(a, b)? == Ordering::Less),
+        )
     });
+
+    // (sort-by cmp array). `cmp` is called with two elements and returns
+    // either whether the first goes before the second, like `<`, or a
+    // number that is negative, zero or positive. The sort is stable.
+    env.insert_typed("sort-by", |env, (func, array): (Callable, Vec<JObject>)| {
+        merge_sort(
+            array,
+            &mut |a, b| match func.call(env, vec![a.clone(), b.clone()])? {
+                JObject::Bool(less) => Ok(less),
+                n => Ok(ordering(&n, &JObject::Number(0))? == Ordering::Less),
+            },
+        )
+    });
+}
+
+/// `(concat array...)` or `(concat string...)`. Shared with `std::string`.
+pub fn concat(env: &mut Environment, args: &[JObject]) -> Result<JObject, Error> {
+    let evaled: Vec<JObject> = args
+        .iter()
+        .map(|x| eval(env, x))
+        .collect::<Result<_, _>>()?;
+    if let Some(JObject::String(_)) = evaled.first() {
+        let strings = evaled
+            .iter()
+            .enumerate()
+            .map(|(i, s)| convert::<String>("concat", i, s))
+            .collect::<Result<Vec<_>, _>>()?;
+        env.allocate(strings.iter().map(|s| s.chars().count()).sum())?;
+        return Ok(JObject::String(strings.concat()));
+    }
+    let lists = evaled
+        .iter()
+        .enumerate()
+        .map(|(i, l)| convert::<Vec<JObject>>("concat", i, l))
+        .collect::<Result<Vec<_>, _>>()?;
+    env.allocate(lists.iter().map(Vec::len).sum())?;
+    Ok(JObject::List(lists.concat()))
+}
+
+/// Splices each list in `array` into the result, charging for its length
+/// up front.
+fn flatten(env: &mut Environment, array: Vec<JObject>) -> Result<Vec<JObject>, Error> {
+    let len = array
+        .iter()
+        .map(|x| match x {
+            JObject::List(l) => l.len(),
+            _ => 1,
+        })
+        .sum();
+    env.allocate(len)?;
+    let mut done = Vec::with_capacity(len);
+    for element in array {
+        match element {
+            JObject::List(l) => done.extend(l),
+            x => done.push(x),
+        }
+    }
+    Ok(done)
+}
+
+/// An index into a sequence of `len` elements, clamped to `0..=len`.
+pub fn clamp(i: i64, len: usize) -> usize {
+    usize::try_from(i.max(0)).map_or(len, |i| i.min(len))
+}
+
+/// The order `sort` uses: numbers by value and strings by code point.
+fn natural_order(a: &JObject, b: &JObject) -> Result<Ordering, Error> {
+    match (a, b) {
+        (JObject::String(a), JObject::String(b)) => Ok(a.cmp(b)),
+        _ if a.as_f64().is_some() && b.as_f64().is_some() => ordering(a, b),
+        _ => Err(Error::new(
+            "bad-type",
+            format!(
+                "sort: can't compare {} {} and {} {}",
+                a.typename(),
+                a,
+                b.typename(),
+                b
+            ),
+        )),
+    }
+}
+
+/// A stable sort with a comparison that can fail. Unlike `slice::sort_by`
+/// it copes with a comparator that isn't a total order.
+fn merge_sort<F>(mut items: Vec<JObject>, less: &mut F) -> Result<Vec<JObject>, Error>
+where
+    F: FnMut(&JObject, &JObject) -> Result<bool, Error>,
+{
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let left = merge_sort(items, less)?;
+    let right = merge_sort(right, less)?;
+
+    let mut done = Vec::with_capacity(left.len() + right.len());
+    let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        // Take from the right only when it's strictly smaller, so equal
+        // elements keep their order.
+        if less(r, l)? {
+            done.push(right.next().unwrap());
+        } else {
+            done.push(left.next().unwrap());
+        }
+    }
+    done.extend(left);
+    done.extend(right);
+    Ok(done)
 }
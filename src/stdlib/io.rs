@@ -1,12 +1,144 @@
+use std::fs;
+use std::io::{self, Read, Write};
+
 use crate::eval::eval;
-use crate::{eval::Environment, json::JObject};
+use crate::{
+    error::Error,
+    eval::Environment,
+    json::{JObject, ParseOptions},
+    serialize,
+};
 
+// Values are written as JSON, like the REPL does, except by `print`. Anything that goes wrong
+// outside the interpreter is an `io-error`; which of these builtins exist
+// at all is up to the environment's `Capabilities`.
 pub fn load_mod(env: &mut Environment) {
     env.insert_builtin("println", |env, args| {
+        let values = shown(env, args)?;
+        write_lines("println", "stdout", &mut io::stdout().lock(), &values)
+    });
+
+    // (print x...) writes plain text with no newline: strings as they are
+    // and anything else as JSON, for scripts that produce output
+    env.insert_builtin("print", |env, args| {
+        let mut values = Vec::new();
         for arg in args {
-            let evaled = eval(env, arg);
-            println!("{}", evaled);
+            values.push(eval(env, arg)?);
+        }
+        write_plain(&mut io::stdout().lock(), &values, env.parse_options)
+            .map_err(|e| io_error("print", "stdout", e))?;
+        Ok(JObject::Null)
+    });
+
+    env.insert_builtin("eprintln", |env, args| {
+        let values = shown(env, args)?;
+        write_lines("eprintln", "stderr", &mut io::stderr().lock(), &values)
+    });
+
+    // (read-line) is the next line of stdin without its newline, or null at
+    // the end of input
+    env.insert_typed("read-line", |_env, (): ()| {
+        let mut line = String::new();
+        let read = io::stdin()
+            .read_line(&mut line)
+            .map_err(|e| io_error("read-line", "stdin", e))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let trimmed = line.strip_suffix('\n').unwrap_or(&line);
+        Ok(Some(
+            trimmed.strip_suffix('\r').unwrap_or(trimmed).to_string(),
+        ))
+    });
+
+    env.insert_typed("read-all-stdin", |_env, (): ()| {
+        let mut all = String::new();
+        io::stdin()
+            .read_to_string(&mut all)
+            .map_err(|e| io_error("read-all-stdin", "stdin", e))?;
+        Ok(all)
+    });
+
+    env.insert_typed("read-file", |_env, (path,): (String,)| {
+        fs::read_to_string(&path).map_err(|e| io_error("read-file", &path, e))
+    });
+
+    // (write-file path s) replaces the file's contents
+    env.insert_typed("write-file", |_env, (path, s): (String, String)| {
+        fs::write(&path, s).map_err(|e| io_error("write-file", &path, e))?;
+        Ok(JObject::Null)
+    });
+
+    // (append-file path s) creates the file if it's missing
+    env.insert_typed("append-file", |_env, (path, s): (String, String)| {
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(s.as_bytes()))
+            .map_err(|e| io_error("append-file", &path, e))?;
+        Ok(JObject::Null)
+    });
+
+    env.insert_typed("file-exists?", |_env, (path,): (String,)| {
+        fs::exists(&path).map_err(|e| io_error("file-exists?", &path, e))
+    });
+
+    // (list-dir path) is the names of the entries in path, sorted
+    env.insert_typed("list-dir", |_env, (path,): (String,)| {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&path).map_err(|e| io_error("list-dir", &path, e))? {
+            let entry = entry.map_err(|e| io_error("list-dir", &path, e))?;
+            names.push(entry.file_name().to_string_lossy().into_owned());
         }
-        JObject::Null
+        names.sort();
+        Ok(names)
+    });
+
+    // (delete-file path) removes a file, not a directory
+    env.insert_typed("delete-file", |_env, (path,): (String,)| {
+        fs::remove_file(&path).map_err(|e| io_error("delete-file", &path, e))?;
+        Ok(JObject::Null)
     });
 }
+
+/// Evaluates each argument and writes it as JSON that reads back the same.
+fn shown(env: &mut Environment, args: &[JObject]) -> Result<Vec<String>, Error> {
+    args.iter()
+        .map(|arg| Ok(serialize::compact_with(&eval(env, arg)?, env.parse_options)))
+        .collect()
+}
+
+/// What `print` writes: strings unquoted and unescaped, everything else as
+/// JSON.
+pub fn write_plain(
+    out: &mut dyn Write,
+    values: &[JObject],
+    options: ParseOptions,
+) -> io::Result<()> {
+    for value in values {
+        match value {
+            JObject::String(s) => out.write_all(s.as_bytes())?,
+            value => out.write_all(serialize::compact_with(value, options).as_bytes())?,
+        }
+    }
+    out.flush()
+}
+
+/// Writes each value on its own line.
+fn write_lines(
+    fname: &str,
+    what: &str,
+    out: &mut dyn Write,
+    values: &[String],
+) -> Result<JObject, Error> {
+    for value in values {
+        writeln!(out, "{}", value).map_err(|e| io_error(fname, what, e))?;
+    }
+    out.flush().map_err(|e| io_error(fname, what, e))?;
+    Ok(JObject::Null)
+}
+
+fn io_error(fname: &str, what: &str, err: io::Error) -> Error {
+    Error::new("io-error", format!("{}: {}: {}", fname, what, err))
+}
@@ -1,30 +1,135 @@
-use crate::eval::{eval, Environment};
-use crate::json::{new_list, JObject, ToJObject};
+use crate::args::arity;
+use crate::error::Error;
+use crate::eval::{eval, Environment, Tail};
+use crate::json::{JObject, ToJObject};
 
-use super::truthy;
+use super::math::equals;
+use super::{body, truthy};
 
 pub fn load_mod(env: &mut Environment) {
-    env.insert_builtin("if", |env, args| {
-        if args.len() != 3 {
-            return new_list(&["error", "bad-arity", &format!("{} != {}", args.len(), 3)]);
-        }
+    env.insert_special("if", |env, args| {
+        arity(args, 3)?;
         if let &[predicate, t, f] = &args {
-            if truthy(&eval(env, predicate)) {
-                eval(env, t)
+            if truthy(&eval(env, predicate)?) {
+                Ok(Tail::Eval(t.clone()))
             } else {
-                eval(env, f)
+                Ok(Tail::Eval(f.clone()))
             }
         } else {
-            JObject::Null
+            Ok(Tail::Return(JObject::Null))
         }
     });
 
     env.insert_builtin("or", |env, args| {
         for arg in args {
-            if truthy(&eval(env, arg)) {
-                return true.to_jobject();
+            if truthy(&eval(env, arg)?) {
+                return Ok(true.to_jobject());
+            }
+        }
+        Ok(false.to_jobject())
+    });
+
+    env.insert_builtin("and", |env, args| {
+        for arg in args {
+            if !truthy(&eval(env, arg)?) {
+                return Ok(false.to_jobject());
+            }
+        }
+        Ok(true.to_jobject())
+    });
+
+    env.insert_builtin("not", |env, args| {
+        arity(args, 1)?;
+        Ok((!truthy(&eval(env, &args[0])?)).to_jobject())
+    });
+
+    env.insert_builtin("xor", |env, args| {
+        arity(args, 2)?;
+        let a = truthy(&eval(env, &args[0])?);
+        let b = truthy(&eval(env, &args[1])?);
+        Ok((a != b).to_jobject())
+    });
+
+    // (= a b ...) is `==` all the way down, so 1 and 1.0 differ
+    env.insert_builtin("=", |env, args| equals(env, args, PartialEq::eq));
+
+    // (when test body...) runs body if test is true, else returns null
+    env.insert_special("when", |env, args| {
+        let Some((test, rest)) = args.split_first() else {
+            return Err(Error::arity(0, 1));
+        };
+        if truthy(&eval(env, test)?) {
+            body(env, rest)
+        } else {
+            Ok(Tail::Return(JObject::Null))
+        }
+    });
+
+    env.insert_special("unless", |env, args| {
+        let Some((test, rest)) = args.split_first() else {
+            return Err(Error::arity(0, 1));
+        };
+        if truthy(&eval(env, test)?) {
+            Ok(Tail::Return(JObject::Null))
+        } else {
+            body(env, rest)
+        }
+    });
+
+    // (cond (test body...) ... (else body...))
+    // A clause with no body returns its test's value.
+    env.insert_special("cond", |env, args| {
+        for clause in args {
+            let Some((test, rest)) = clause_parts(clause) else {
+                return Err(Error::new("bad-cond", format!("bad clause {}", clause)));
+            };
+            if is_else(test) {
+                return body(env, rest);
+            }
+            let value = eval(env, test)?;
+            if truthy(&value) {
+                if rest.is_empty() {
+                    return Ok(Tail::Return(value));
+                }
+                return body(env, rest);
             }
         }
-        false.to_jobject()
+        Ok(Tail::Return(JObject::Null))
     });
+
+    // (case key ((1 2) body...) ("x" body...) (else body...))
+    // The values in a clause aren't evaluated. A single value that isn't a
+    // list stands for a list of one.
+    env.insert_special("case", |env, args| {
+        let Some((key, clauses)) = args.split_first() else {
+            return Err(Error::arity(0, 1));
+        };
+        let key = eval(env, key)?;
+        for clause in clauses {
+            let Some((values, rest)) = clause_parts(clause) else {
+                return Err(Error::new("bad-case", format!("bad clause {}", clause)));
+            };
+            let matched = match values {
+                _ if is_else(values) => true,
+                JObject::List(values) => values.contains(&key),
+                value => *value == key,
+            };
+            if matched {
+                return body(env, rest);
+            }
+        }
+        Ok(Tail::Return(JObject::Null))
+    });
+}
+
+/// Splits a `cond` or `case` clause into its head and body.
+fn clause_parts(clause: &JObject) -> Option<(&JObject, &[JObject])> {
+    match clause {
+        JObject::List(l) => l.split_first(),
+        _ => None,
+    }
+}
+
+fn is_else(o: &JObject) -> bool {
+    matches!(o, JObject::String(s) if s == "else")
 }
@@ -0,0 +1,268 @@
+use std::cmp::Ordering;
+
+use crate::args::{arity, min_arity};
+use crate::error::Error;
+use crate::eval::{eval, Environment};
+use crate::json::{promote, JObject, Numbers, ToJObject};
+
+type IntOp = fn(i64, i64) -> Option<i64>;
+type FloatOp = fn(f64, f64) -> f64;
+
+// Arithmetic follows `json::promote`: two Numbers give a Number (division
+// truncates), and a Float on either side gives a Float.
+pub fn load_mod(env: &mut Environment) {
+    env.insert_builtin("+", |env, args| {
+        fold(
+            env,
+            args,
+            "+",
+            JObject::Number(0),
+            i64::checked_add,
+            |a, b| a + b,
+        )
+    });
+
+    env.insert_builtin("*", |env, args| {
+        fold(
+            env,
+            args,
+            "*",
+            JObject::Number(1),
+            i64::checked_mul,
+            |a, b| a * b,
+        )
+    });
+
+    // (- x) negates, (- x y z) is x - y - z
+    env.insert_builtin("-", |env, args| {
+        if args.len() == 1 {
+            let x = eval(env, &args[0])?;
+            return arith("-", &JObject::Number(0), &x, i64::checked_sub, |a, b| a - b);
+        }
+        reduce(env, args, "-", i64::checked_sub, |a, b| a - b)
+    });
+
+    // (/ x) is 1 / x, (/ x y z) is x / y / z
+    env.insert_builtin("/", |env, args| {
+        if args.len() == 1 {
+            let x = eval(env, &args[0])?;
+            return divide("/", &JObject::Number(1), &x, i64::checked_div, |a, b| a / b);
+        }
+        reduce_by(env, args, |a, b| {
+            divide("/", a, b, i64::checked_div, |a, b| a / b)
+        })
+    });
+
+    env.insert_builtin("mod", |env, args| {
+        arity(args, 2)?;
+        let (a, b) = (eval(env, &args[0])?, eval(env, &args[1])?);
+        divide("mod", &a, &b, i64::checked_rem, |a, b| a % b)
+    });
+
+    env.insert_builtin("<", |env, args| compare(env, args, |o| o == Ordering::Less));
+    env.insert_builtin("<=", |env, args| {
+        compare(env, args, |o| o != Ordering::Greater)
+    });
+    env.insert_builtin(">", |env, args| {
+        compare(env, args, |o| o == Ordering::Greater)
+    });
+    env.insert_builtin(">=", |env, args| {
+        compare(env, args, |o| o != Ordering::Less)
+    });
+
+    // `=` and `!=` compare numbers by value, so 1 equals 1.0, in lists and
+    // maps too. std::logic's `=` is plain `==` instead.
+    env.insert_builtin("=", |env, args| equals(env, args, equal));
+
+    env.insert_builtin("!=", |env, args| {
+        arity(args, 2)?;
+        let (a, b) = (eval(env, &args[0])?, eval(env, &args[1])?);
+        Ok((!equal(&a, &b)).to_jobject())
+    });
+
+    env.insert_builtin("min", |env, args| extremum(env, args, Ordering::Less));
+    env.insert_builtin("max", |env, args| extremum(env, args, Ordering::Greater));
+
+    env.insert_builtin("abs", |env, args| {
+        arity(args, 1)?;
+        match eval(env, &args[0])? {
+            JObject::Number(n) => n
+                .checked_abs()
+                .map(JObject::Number)
+                .ok_or_else(|| Error::new("overflow", format!("abs {}", n))),
+            JObject::Float(n) => Ok(JObject::Float(n.abs())),
+            x => Err(not_a_number(&x)),
+        }
+    });
+}
+
+/// `(= a b ...)`: whether each argument is `eq` to the next. Shared with
+/// `std::logic`.
+pub fn equals(
+    env: &mut Environment,
+    args: &[JObject],
+    eq: fn(&JObject, &JObject) -> bool,
+) -> Result<JObject, Error> {
+    min_arity(args, 2)?;
+    let evaled: Vec<JObject> = args
+        .iter()
+        .map(|x| eval(env, x))
+        .collect::<Result<_, _>>()?;
+    Ok(evaled
+        .windows(2)
+        .all(|pair| eq(&pair[0], &pair[1]))
+        .to_jobject())
+}
+
+/// Deep equality where numbers compare by value, so `1` equals `1.0` even
+/// inside lists and maps. Everything else uses `==`.
+fn equal(a: &JObject, b: &JObject) -> bool {
+    match (a, b) {
+        (JObject::List(a), JObject::List(b)) => {
+            a.len() == b.len() && std::iter::zip(a, b).all(|(a, b)| equal(a, b))
+        }
+        (JObject::Map(a), JObject::Map(b)) => {
+            a.len() == b.len() && a.iter().all(|(k, v)| b.get(k).is_some_and(|w| equal(v, w)))
+        }
+        _ => match promote(a, b) {
+            Some(Numbers::Ints(a, b)) => a == b,
+            Some(Numbers::Floats(a, b)) => a == b,
+            None => a == b,
+        },
+    }
+}
+
+fn not_a_number(x: &JObject) -> Error {
+    Error::new("not-a-number", x.to_string())
+}
+
+fn overflow(op: &str, a: &JObject, b: &JObject) -> Error {
+    Error::new("overflow", format!("{} {} {}", a, op, b))
+}
+
+fn arith(
+    name: &str,
+    a: &JObject,
+    b: &JObject,
+    int: IntOp,
+    float: FloatOp,
+) -> Result<JObject, Error> {
+    match promote(a, b) {
+        Some(Numbers::Ints(x, y)) => int(x, y)
+            .map(JObject::Number)
+            .ok_or_else(|| overflow(name, a, b)),
+        Some(Numbers::Floats(x, y)) => match float(x, y) {
+            n if n.is_finite() => Ok(JObject::Float(n)),
+            _ => Err(overflow(name, a, b)),
+        },
+        None if a.as_f64().is_none() => Err(not_a_number(a)),
+        None => Err(not_a_number(b)),
+    }
+}
+
+fn divide(
+    name: &str,
+    a: &JObject,
+    b: &JObject,
+    int: IntOp,
+    float: FloatOp,
+) -> Result<JObject, Error> {
+    if b.as_f64() == Some(0.0) {
+        return Err(Error::new("division-by-zero", a.to_string()));
+    }
+    arith(name, a, b, int, float)
+}
+
+fn fold(
+    env: &mut Environment,
+    args: &[JObject],
+    name: &str,
+    init: JObject,
+    int: IntOp,
+    float: FloatOp,
+) -> Result<JObject, Error> {
+    let mut acc = init;
+    for arg in args {
+        let x = eval(env, arg)?;
+        acc = arith(name, &acc, &x, int, float)?;
+    }
+    Ok(acc)
+}
+
+fn reduce(
+    env: &mut Environment,
+    args: &[JObject],
+    name: &str,
+    int: IntOp,
+    float: FloatOp,
+) -> Result<JObject, Error> {
+    reduce_by(env, args, |a, b| arith(name, a, b, int, float))
+}
+
+fn reduce_by(
+    env: &mut Environment,
+    args: &[JObject],
+    op: impl Fn(&JObject, &JObject) -> Result<JObject, Error>,
+) -> Result<JObject, Error> {
+    let Some((first, rest)) = args.split_first() else {
+        return Err(Error::arity(0, 1));
+    };
+    let mut acc = eval(env, first)?;
+    if acc.as_f64().is_none() {
+        return Err(not_a_number(&acc));
+    }
+    for arg in rest {
+        let x = eval(env, arg)?;
+        acc = op(&acc, &x)?;
+    }
+    Ok(acc)
+}
+
+/// How two numbers compare, or `not-a-number` if either isn't one.
+pub fn ordering(a: &JObject, b: &JObject) -> Result<Ordering, Error> {
+    match promote(a, b) {
+        Some(Numbers::Ints(x, y)) => Ok(x.cmp(&y)),
+        // NaN isn't ordered, and -0.0 is the same as 0.0.
+        Some(Numbers::Floats(x, y)) => x
+            .partial_cmp(&y)
+            .ok_or_else(|| not_a_number(if x.is_nan() { a } else { b })),
+        None if a.as_f64().is_none() => Err(not_a_number(a)),
+        None => Err(not_a_number(b)),
+    }
+}
+
+/// Checks `holds` for each adjacent pair, so `(< a b c)` means a < b < c.
+fn compare(
+    env: &mut Environment,
+    args: &[JObject],
+    holds: fn(Ordering) -> bool,
+) -> Result<JObject, Error> {
+    min_arity(args, 2)?;
+    let evaled: Vec<JObject> = args
+        .iter()
+        .map(|x| eval(env, x))
+        .collect::<Result<_, _>>()?;
+    for pair in evaled.windows(2) {
+        if !holds(ordering(&pair[0], &pair[1])?) {
+            return Ok(false.to_jobject());
+        }
+    }
+    Ok(true.to_jobject())
+}
+
+fn extremum(env: &mut Environment, args: &[JObject], wanted: Ordering) -> Result<JObject, Error> {
+    let Some((first, rest)) = args.split_first() else {
+        return Err(Error::arity(0, 1));
+    };
+    let mut best = eval(env, first)?;
+    if best.as_f64().is_none() {
+        return Err(not_a_number(&best));
+    }
+    for arg in rest {
+        let x = eval(env, arg)?;
+        if ordering(&x, &best)? == wanted {
+            best = x;
+        }
+    }
+    Ok(best)
+}
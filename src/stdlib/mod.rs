@@ -1,26 +1,52 @@
+use crate::args::arity;
+use crate::error::Error;
 use crate::eval::eval;
-use crate::eval::Environment;
+use crate::eval::{Environment, Scope, Tail};
 use crate::json::JObject;
-use crate::json::{new_list, ToJObject};
+use crate::json::ToJObject;
 
 pub mod array;
 pub mod io;
 pub mod logic;
+pub mod math;
 pub mod object;
+pub mod regex;
+pub mod string;
 
-pub fn import_builtin_library(env: &mut Environment, name: &str) -> JObject {
+/// Modules that can't touch anything outside the interpreter.
+pub const PURE_MODULES: &[&str] = &[
+    "std::array",
+    "std::object",
+    "std::logic",
+    "std::math",
+    "std::regex",
+    "std::string",
+];
+
+pub fn import_builtin_library(env: &mut Environment, name: &str) -> Result<JObject, Error> {
+    if !env.capabilities.allows_module(name) {
+        return Err(Error::new(
+            "import-denied",
+            format!("{} is not allowed in this environment", name),
+        ));
+    }
     // TODO: Is there a better way to import libraries?
     match name {
         "std::io" => io::load_mod(env),
         "std::array" => array::load_mod(env),
         "std::object" => object::load_mod(env),
         "std::logic" => logic::load_mod(env),
+        "std::math" => math::load_mod(env),
+        "std::regex" => regex::load_mod(env),
+        "std::string" => string::load_mod(env),
         _ => {
-            println!("builtin library not found: {}", name);
-            return new_list(&["error", "bad-import"]);
+            return Err(Error::new(
+                "bad-import",
+                format!("builtin library not found: {}", name),
+            ))
         }
     }
-    "ok".to_jobject()
+    Ok("ok".to_jobject())
 }
 
 pub fn load_mod(env: &mut Environment) {
@@ -28,120 +54,208 @@ pub fn load_mod(env: &mut Environment) {
         let mut last_import = "ok".to_jobject();
         for arg in args {
             if let JObject::String(s) = arg {
-                last_import = import_builtin_library(env, s);
+                last_import = import_builtin_library(env, s)?;
             } else {
-                println!("{}: not module name", arg);
-                return new_list(&["error", "bad-import"]);
+                return Err(Error::new(
+                    "bad-import",
+                    format!("{}: not module name", arg),
+                ));
             }
         }
-        last_import
+        Ok(last_import)
     });
 
     env.insert_builtin("type", |env, args| {
-        let evaled: Vec<JObject> = args.iter().map(|x| eval(env, x)).collect();
+        let evaled: Vec<JObject> = args
+            .iter()
+            .map(|x| eval(env, x))
+            .collect::<Result<_, _>>()?;
         if evaled.len() == 1 {
-            evaled[0].typename().to_jobject()
+            Ok(evaled[0].typename().to_jobject())
         } else {
             let x: Vec<JObject> = evaled.iter().map(|x| x.typename().to_jobject()).collect();
-            JObject::List(x)
+            Ok(JObject::List(x))
         }
     });
 
     env.insert_builtin("->string", |env, args| {
-        if args.len() != 1 {
-            return new_list(&["error", "bad-arity", &format!("{} != {}", args.len(), 1)]);
-        }
-        JObject::String(eval(env, &args[0]).to_string())
+        arity(args, 1)?;
+        Ok(JObject::String(eval(env, &args[0])?.to_string()))
     });
 
     env.insert_builtin("quote", |_env, args| {
-        if args.len() != 1 {
-            return new_list(&["error", "bad-arity", &format!("{} != {}", args.len(), 1)]);
-        }
-        args[0].clone()
+        arity(args, 1)?;
+        Ok(args[0].clone())
     });
 
     env.insert_builtin("quasiquote", |env, args| {
-        if args.len() != 1 {
-            return new_list(&["error", "bad-arity", &format!("{} != {}", args.len(), 1)]);
-        }
+        arity(args, 1)?;
         quasiwalk(env, &args[0])
     });
 
     env.insert_builtin("def", |env, args| {
-        if args.len() != 2 {
-            return new_list(&["error", "bad-arity", &format!("{} != {}", args.len(), 2)]);
-        }
-        if let JObject::String(s) = args[0].clone() {
-            let body = eval(env, &args[1]);
-            env.symbols.insert(s, body.clone());
-            body
+        arity(args, 2)?;
+        if let Some(s) = args[0].name() {
+            let body = eval(env, &args[1])?;
+            env.symbols.insert(s.to_string(), body.clone());
+            Ok(body)
         } else {
-            panic!("you can't assign a non-string to a value");
+            Err(Error::new(
+                "bad-name",
+                format!("you can't assign to a non-string: {}", args[0]),
+            ))
         }
     });
 
-    env.insert_builtin("f", |_env, args| {
-        if args.len() != 2 {
-            return new_list(&["error", "bad-arity", &format!("{} != {}", args.len(), 2)]);
-        }
-        if let JObject::List(fbody_args) = args[0].clone() {
-            let argsyms = fbody_args
-                .iter()
-                .map(|x| {
-                    if let JObject::String(s) = x {
-                        s.as_str()
-                    } else {
-                        panic!("can't use {:?} as function arg", x)
-                    }
-                })
-                .collect();
+    env.insert_builtin("f", |env, args| {
+        arity(args, 2)?;
+        let argsyms = parameters("function", &args[0])?;
+        Ok(JObject::new_func(argsyms, args[1].clone()).capturing(env.scope.clone()))
+    });
 
-            JObject::new_func(argsyms, args[1].clone())
-        } else {
-            JObject::Null
-        }
+    env.insert_builtin("macro", |env, args| {
+        arity(args, 2)?;
+        let argsyms = parameters("macro", &args[0])?;
+        Ok(JObject::new_macro(argsyms, args[1].clone()).capturing(env.scope.clone()))
     });
 
-    env.insert_builtin("macro", |_env, args| {
-        if args.len() != 2 {
-            return new_list(&["error", "bad-arity", &format!("{} != {}", args.len(), 2)]);
-        }
-        if let JObject::List(fbody_args) = args[0].clone() {
-            let argsyms = fbody_args
-                .iter()
-                .map(|x| {
-                    if let JObject::String(s) = x {
-                        s.as_str()
-                    } else {
-                        panic!("can't use {:?} as macro arg", x)
-                    }
-                })
-                .collect();
+    env.insert_special("program", body);
+    env.insert_special("do", body);
 
-            JObject::new_macro(argsyms, args[1].clone())
-        } else {
-            JObject::Null
+    // (let ((x 1) (y (+ x 1))) body...)
+    // Each binding sees the ones before it, but not itself.
+    env.insert_special("let", |env, args| {
+        let Some((bindings, rest)) = args.split_first() else {
+            return Err(Error::new("bad-let", "let needs a list of bindings"));
+        };
+        for (name, value) in let_bindings("let", bindings)? {
+            let value = eval(env, value)?;
+            let frame = Scope::new(env.scope.clone());
+            frame.set(name, value);
+            env.scope = Some(frame);
         }
+        body(env, rest)
     });
 
-    env.insert_builtin("program", |env, args| {
-        let mut last_expression = JObject::Null;
-        for arg in args {
-            last_expression = eval(env, arg)
+    // (letrec ((even? (f (n) ... odd? ...)) (odd? ...)) body...)
+    // All bindings share one frame, so functions can call each other.
+    env.insert_special("letrec", |env, args| {
+        let Some((bindings, rest)) = args.split_first() else {
+            return Err(Error::new("bad-let", "letrec needs a list of bindings"));
+        };
+        let bindings = let_bindings("letrec", bindings)?;
+        let frame = Scope::new(env.scope.clone());
+        env.scope = Some(frame.clone());
+        for (name, value) in bindings {
+            let value = eval(env, value)?;
+            frame.set_recursive(name, value);
         }
-        last_expression
+        body(env, rest)
     });
 
     env.insert_builtin("crash", |_env, _args| {
         unsafe { std::ptr::null_mut::<i8>().write(1) };
-        JObject::Null
+        Ok(JObject::Null)
     });
+
+    env.insert_builtin("throw", |env, args| {
+        arity(args, 1)?;
+        Err(Error::Thrown(eval(env, &args[0])?))
+    });
+
+    // (try body (catch e handler) (finally cleanup))
+    env.insert_builtin("try", |env, args| {
+        let Some((body, clauses)) = args.split_first() else {
+            return Err(Error::arity(0, 1));
+        };
+        let mut handler = None;
+        let mut cleanup = None;
+        for clause in clauses {
+            match clause {
+                JObject::List(l) => match l.as_slice() {
+                    [JObject::String(kw), name, h] if kw == "catch" && name.name().is_some() => {
+                        handler = Some((name.name().unwrap(), h))
+                    }
+                    [JObject::String(kw), c] if kw == "finally" => cleanup = Some(c),
+                    _ => return Err(Error::new("bad-try", format!("bad clause {}", clause))),
+                },
+                _ => return Err(Error::new("bad-try", format!("bad clause {}", clause))),
+            }
+        }
+
+        let mut res = eval(env, body);
+        let caught = res.as_ref().is_err_and(|err| err.is_catchable());
+        if let (true, Err(err), Some((name, h))) = (caught, &res, handler) {
+            let frame = Scope::new(env.scope.clone());
+            frame.set(name, err.to_jobject());
+            let outer = env.scope.replace(frame);
+            res = eval(env, h);
+            env.scope = outer;
+        }
+        if let Some(c) = cleanup {
+            eval(env, c)?;
+        }
+        res
+    });
+}
+
+/// Evaluates each form, leaving the last one in tail position. Frames set
+/// up by the caller, like `let`'s, stay in place for the tail.
+fn body(env: &mut Environment, forms: &[JObject]) -> Result<Tail, Error> {
+    let Some((last, init)) = forms.split_last() else {
+        return Ok(Tail::Return(JObject::Null));
+    };
+    for form in init {
+        eval(env, form)?;
+    }
+    Ok(Tail::Eval(last.clone()))
+}
+
+/// Reads `[["name", value], ...]` from a `let` or `letrec`.
+fn let_bindings<'a>(what: &str, o: &'a JObject) -> Result<Vec<(&'a str, &'a JObject)>, Error> {
+    let bad = || {
+        Error::new(
+            "bad-let",
+            format!("{} bindings must be [name, value] pairs: {}", what, o),
+        )
+    };
+    let JObject::List(bindings) = o else {
+        return Err(bad());
+    };
+    bindings
+        .iter()
+        .map(|b| match b {
+            JObject::List(pair) => match pair.as_slice() {
+                [name, value] => Ok((name.name().ok_or_else(bad)?, value)),
+                _ => Err(bad()),
+            },
+            _ => Err(bad()),
+        })
+        .collect()
+}
+
+/// Reads the parameter list of a function or macro literal.
+fn parameters<'a>(what: &str, o: &'a JObject) -> Result<Vec<&'a str>, Error> {
+    let JObject::List(params) = o else {
+        return Err(Error::new(
+            "bad-parameter",
+            format!("{} parameters must be a list: {}", what, o),
+        ));
+    };
+    params
+        .iter()
+        .map(|x| {
+            x.name().ok_or_else(|| {
+                Error::new("bad-parameter", format!("can't use {} as {} arg", x, what))
+            })
+        })
+        .collect()
 }
 
-fn quasiwalk(env: &mut Environment, o: &JObject) -> JObject {
+fn quasiwalk(env: &mut Environment, o: &JObject) -> Result<JObject, Error> {
     if let JObject::List(l) = o {
-        if l.len() > 1 && l[0] == "unquote".to_jobject() {
+        if l.first() == Some(&"unquote".to_jobject()) {
+            arity(&l[1..], 1)?;
             return eval(env, &l[1].clone());
         }
         // else we check if anything should be spliced
@@ -150,7 +264,8 @@ fn quasiwalk(env: &mut Environment, o: &JObject) -> JObject {
             if let JObject::List(l) = x {
                 if let Some(JObject::String(s)) = l.first() {
                     if s == "splice-unquote" {
-                        done.push(eval(env, &l[1]));
+                        arity(&l[1..], 1)?;
+                        done.push(eval(env, &l[1])?);
                     } else {
                         done.push(x.clone());
                     }
@@ -161,11 +276,14 @@ fn quasiwalk(env: &mut Environment, o: &JObject) -> JObject {
                 done.push(x.clone());
             }
         }
-        return JObject::List(done);
+        return Ok(JObject::List(done));
     }
-    o.clone()
+    Ok(o.clone())
 }
 
+/// What conditionals treat as false: `false`, `null` and the empty list.
+/// Everything else, including `0` and `""`, is true. `null` and `[]` are
+/// still different values, they're just both false.
 fn truthy(o: &JObject) -> bool {
     match o {
         JObject::Null => false,
@@ -1,40 +1,265 @@
 use crate::{
-    eval::Environment,
-    json::{new_list, JObject, ToJObject},
+    args::{convert, typed, Callable},
+    error::Error,
+    eval::{eval, Environment},
+    json::JObject,
+    map::OrderedMap,
 };
 
-use crate::eval::eval;
+use super::truthy;
 
+type Map = OrderedMap<Box<JObject>>;
+
+// Functions that take a callback take it first, like `map`. Paths are lists
+// of keys, where a number can also index into a list.
 pub fn load_mod(env: &mut Environment) {
-    env.insert_builtin("contains-key", |env, args| {
-        if args.len() != 2 {
-            println!("bar arity");
-            return new_list(&["error", "bad-arity", &format!("{} != {}", args.len(), 2)]);
-        }
+    env.insert_typed("contains-key", |_env, (map, key): (Map, JObject)| {
+        Ok(map.contains_key(&key_string("contains-key", 1, &key)?))
+    });
 
-        match (eval(env, &args[0]), eval(env, &args[1])) {
-            (JObject::Map(map), JObject::String(key)) => map.contains_key(&key).to_jobject(),
-            _ => JObject::Bool(false),
-        }
+    env.insert_typed(
+        "insert",
+        |_env, (mut map, key, value): (Map, JObject, JObject)| {
+            map.insert(key_string("insert", 1, &key)?, Box::new(value));
+            Ok(JObject::Map(map))
+        },
+    );
+
+    // (get map key) or (get map key default)
+    env.insert_builtin("get", |env, args| {
+        let (map, key, default) = match args.len() {
+            2 => {
+                let (map, key): (Map, JObject) = typed(env, "get", args)?;
+                (map, key, JObject::Null)
+            }
+            _ => typed::<(Map, JObject, JObject)>(env, "get", args)?,
+        };
+        let key = key_string("get", 1, &key)?;
+        Ok(map.get(&key).map_or(default, |v| (**v).clone()))
     });
 
-    env.insert_builtin("insert", |env, args| {
-        if let &[map, key, value] = &args {
-            match (eval(env, map), eval(env, key), eval(env, value)) {
-                (JObject::Map(map), JObject::String(key), value) => {
-                    let mut new_map = map.clone();
-                    new_map.insert(key.clone(), Box::new(value.clone()));
-                    JObject::Map(new_map)
+    env.insert_typed("remove", |_env, (mut map, key): (Map, JObject)| {
+        map.remove(&key_string("remove", 1, &key)?);
+        Ok(map)
+    });
+
+    env.insert_typed("keys", |_env, (map,): (Map,)| {
+        Ok(map.keys().cloned().collect::<Vec<String>>())
+    });
+
+    env.insert_typed("values", |_env, (map,): (Map,)| {
+        Ok(map.into_iter().map(|(_, v)| *v).collect::<Vec<JObject>>())
+    });
+
+    // (entries map) is [[key, value], ...]
+    env.insert_typed("entries", |_env, (map,): (Map,)| {
+        Ok(map
+            .into_iter()
+            .map(|(k, v)| JObject::List(vec![JObject::String(k), *v]))
+            .collect::<Vec<JObject>>())
+    });
+
+    env.insert_typed("from-entries", |_env, (entries,): (Vec<JObject>,)| {
+        let mut map = Map::new();
+        for entry in entries {
+            match entry {
+                JObject::List(pair) if pair.len() == 2 => {
+                    let [key, value]: [JObject; 2] = pair.try_into().unwrap();
+                    map.insert(key_string("from-entries", 0, &key)?, Box::new(value));
                 }
-                (JObject::Map(map), JObject::Number(key), value) => {
-                    let mut new_map = map.clone();
-                    new_map.insert(key.to_string(), Box::new(value.clone()));
-                    JObject::Map(new_map)
+                entry => {
+                    return Err(Error::new(
+                        "bad-type",
+                        format!("from-entries: {} is not a [key, value] pair", entry),
+                    ))
                 }
-                _x => JObject::Null,
             }
-        } else {
-            JObject::Null
         }
+        Ok(map)
+    });
+
+    // (merge map...) where later maps win
+    env.insert_builtin("merge", |env, args| {
+        let mut done = Map::new();
+        for map in maps(env, "merge", args)? {
+            for (k, v) in map {
+                done.insert(k, v);
+            }
+        }
+        Ok(JObject::Map(done))
+    });
+
+    // (deep-merge map...) also merges maps nested under the same key
+    env.insert_builtin("deep-merge", |env, args| {
+        let done = maps(env, "deep-merge", args)?
+            .into_iter()
+            .fold(Map::new(), deep_merge);
+        Ok(JObject::Map(done))
+    });
+
+    // (update map key fn) replaces the value with (fn value), null if absent
+    env.insert_typed(
+        "update",
+        |env, (map, key, func): (Map, JObject, Callable)| {
+            let path = [JObject::String(key_string("update", 1, &key)?)];
+            update_in(JObject::Map(map), &path, &mut |old| {
+                func.call(env, vec![old])
+            })
+        },
+    );
+
+    // (select-keys map keys) keeps the keys that are present, in map order
+    env.insert_typed("select-keys", |_env, (map, keys): (Map, Vec<JObject>)| {
+        let keys = keys
+            .iter()
+            .map(|k| key_string("select-keys", 1, k))
+            .collect::<Result<Vec<String>, Error>>()?;
+        Ok(map
+            .into_iter()
+            .filter(|(k, _)| keys.contains(k))
+            .collect::<Map>())
+    });
+
+    env.insert_typed("map-values", |env, (func, map): (Callable, Map)| {
+        let mut done = Map::new();
+        for (k, v) in map {
+            done.insert(k, Box::new(func.call(env, vec![*v])?));
+        }
+        Ok(done)
+    });
+
+    // (filter-entries fn map) keeps the entries where (fn key value) is true
+    env.insert_typed("filter-entries", |env, (func, map): (Callable, Map)| {
+        let mut done = Map::new();
+        for (k, v) in map {
+            if truthy(&func.call(env, vec![JObject::String(k.clone()), (*v).clone()])?) {
+                done.insert(k, v);
+            }
+        }
+        Ok(done)
     });
+
+    // (get-in o path) or (get-in o path default)
+    env.insert_builtin("get-in", |env, args| {
+        let (o, path, default) = match args.len() {
+            2 => {
+                let (o, path): (JObject, Vec<JObject>) = typed(env, "get-in", args)?;
+                (o, path, JObject::Null)
+            }
+            _ => typed::<(JObject, Vec<JObject>, JObject)>(env, "get-in", args)?,
+        };
+        check_path("get-in", &path)?;
+        Ok(get_in(&o, &path).cloned().unwrap_or(default))
+    });
+
+    // (assoc-in o path value) makes maps for any missing keys on the way
+    env.insert_typed(
+        "assoc-in",
+        |_env, (o, path, value): (JObject, Vec<JObject>, JObject)| {
+            check_path("assoc-in", &path)?;
+            update_in(o, &path, &mut |_| Ok(value.clone()))
+        },
+    );
+
+    // (update-in o path fn)
+    env.insert_typed(
+        "update-in",
+        |env, (o, path, func): (JObject, Vec<JObject>, Callable)| {
+            check_path("update-in", &path)?;
+            update_in(o, &path, &mut |old| func.call(env, vec![old]))
+        },
+    );
+}
+
+/// Map keys are strings, but numbers are accepted and stringified.
+fn key_string(fname: &str, index: usize, key: &JObject) -> Result<String, Error> {
+    match key {
+        JObject::Number(n) => Ok(n.to_string()),
+        key => convert(fname, index, key),
+    }
+}
+
+/// Evaluates every argument as a map.
+fn maps(env: &mut Environment, fname: &str, args: &[JObject]) -> Result<Vec<Map>, Error> {
+    args.iter()
+        .enumerate()
+        .map(|(i, arg)| convert(fname, i, &eval(env, arg)?))
+        .collect()
+}
+
+fn deep_merge(mut into: Map, from: Map) -> Map {
+    for (k, v) in from {
+        let merged = match (into.get(&k).map(|v| &**v), *v) {
+            (Some(JObject::Map(old)), JObject::Map(new)) => {
+                JObject::Map(deep_merge(old.clone(), new))
+            }
+            (_, new) => new,
+        };
+        into.insert(k, Box::new(merged));
+    }
+    into
+}
+
+fn check_path(fname: &str, path: &[JObject]) -> Result<(), Error> {
+    match path
+        .iter()
+        .find(|k| !matches!(k, JObject::String(_) | JObject::Number(_)))
+    {
+        Some(k) => Err(Error::new(
+            "bad-path",
+            format!("{}: path keys are strings or numbers, not {}", fname, k),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn get_in<'a>(o: &'a JObject, path: &[JObject]) -> Option<&'a JObject> {
+    let Some((key, rest)) = path.split_first() else {
+        return Some(o);
+    };
+    let child = match (o, key) {
+        (JObject::Map(m), JObject::String(k)) => m.get(k).map(|v| &**v),
+        (JObject::Map(m), JObject::Number(n)) => m.get(&n.to_string()).map(|v| &**v),
+        (JObject::List(l), JObject::Number(n)) => usize::try_from(*n).ok().and_then(|i| l.get(i)),
+        _ => None,
+    }?;
+    get_in(child, rest)
+}
+
+/// Replaces the value at `path` with `f` of it. Missing keys, and `null`s
+/// on the way, become maps. List indices must already exist.
+fn update_in(
+    o: JObject,
+    path: &[JObject],
+    f: &mut dyn FnMut(JObject) -> Result<JObject, Error>,
+) -> Result<JObject, Error> {
+    let Some((key, rest)) = path.split_first() else {
+        return f(o);
+    };
+    match (o, key) {
+        (JObject::List(mut l), JObject::Number(n)) => {
+            let Some(i) = usize::try_from(*n).ok().filter(|&i| i < l.len()) else {
+                return Err(Error::new(
+                    "bad-path",
+                    format!("index {} out of range for a list of {}", n, l.len()),
+                ));
+            };
+            let child = std::mem::replace(&mut l[i], JObject::Null);
+            l[i] = update_in(child, rest, f)?;
+            Ok(JObject::List(l))
+        }
+        (JObject::Map(mut m), key) => {
+            let key = key_string("path", 0, key)?;
+            let child = m.get_mut(&key).map_or(JObject::Null, |v| {
+                std::mem::replace(&mut **v, JObject::Null)
+            });
+            m.insert(key, Box::new(update_in(child, rest, f)?));
+            Ok(JObject::Map(m))
+        }
+        (JObject::Null, _) => update_in(JObject::Map(Map::new()), path, f),
+        (o, key) => Err(Error::new(
+            "bad-path",
+            format!("can't look up {} in {} {}", key, o.typename(), o),
+        )),
+    }
 }
@@ -0,0 +1,175 @@
+use crate::{
+    args::{convert, typed, Callable},
+    error::Error,
+    eval::Environment,
+    json::JObject,
+    map::OrderedMap,
+    regex::{Captures, Regex},
+};
+
+// Every function takes the pattern first, then the text. Patterns are
+// compiled on each call.
+pub fn load_mod(env: &mut Environment) {
+    // (matches? pattern s) is whether all of s matches
+    env.insert_typed("matches?", |env, (pattern, s): (String, String)| {
+        let chars: Vec<char> = s.chars().collect();
+        Regex::new(&pattern)?.is_full_match(&chars, &mut |n| env.charge(n))
+    });
+
+    // (re-find pattern s) is the first match, or null. It isn't `find` so
+    // that std::array's `find` still works alongside it.
+    env.insert_typed("re-find", |env, (pattern, s): (String, String)| {
+        let chars: Vec<char> = s.chars().collect();
+        let found = Regex::new(&pattern)?.find_at(&chars, 0, &mut |n| env.charge(n))?;
+        Ok(found.map_or(JObject::Null, |caps| slice(&chars, caps[0])))
+    });
+
+    env.insert_typed("find-all", |env, (pattern, s): (String, String)| {
+        let chars: Vec<char> = s.chars().collect();
+        Ok(Regex::new(&pattern)?
+            .find_all(&chars, &mut |n| env.charge(n))?
+            .into_iter()
+            .map(|caps| slice(&chars, caps[0]))
+            .collect::<Vec<JObject>>())
+    });
+
+    // (captures pattern s) describes the first match as a map, or is null.
+    // See `captures_map` for what's in it.
+    env.insert_typed("captures", |env, (pattern, s): (String, String)| {
+        let regex = Regex::new(&pattern)?;
+        let chars: Vec<char> = s.chars().collect();
+        Ok(regex
+            .find_at(&chars, 0, &mut |n| env.charge(n))?
+            .map(|caps| captures_map(&regex, &chars, &caps)))
+    });
+
+    // (replace-all pattern s replacement). The replacement is either a
+    // string, where `$1`, `$name` and `${name}` insert groups and `$$` is
+    // a dollar sign, or a function called with each match's `captures` map.
+    env.insert_builtin("replace-all", |env, args| {
+        let (pattern, s, replacement): (String, String, JObject) = typed(env, "replace-all", args)?;
+        let regex = Regex::new(&pattern)?;
+        let chars: Vec<char> = s.chars().collect();
+        let mut out = String::new();
+        let mut last = 0;
+        for caps in regex.find_all(&chars, &mut |n| env.charge(n))? {
+            let (start, end) = caps[0].unwrap();
+            out.extend(&chars[last..start]);
+            match &replacement {
+                JObject::Func { .. } | JObject::Macro { .. } => {
+                    let func = Callable(replacement.clone());
+                    let map = captures_map(&regex, &chars, &caps);
+                    let value = func.call(env, vec![map])?;
+                    out.push_str(&convert::<String>("replace-all", 2, &value)?);
+                }
+                template => {
+                    let template = convert::<String>("replace-all", 2, template)?;
+                    expand(&mut out, &template, &regex, &chars, &caps)?;
+                }
+            }
+            last = end;
+        }
+        out.extend(&chars[last..]);
+        Ok(JObject::String(out))
+    });
+
+    // (split-by pattern s) is the text between matches
+    env.insert_typed("split-by", |env, (pattern, s): (String, String)| {
+        let chars: Vec<char> = s.chars().collect();
+        let mut pieces = Vec::new();
+        let mut last = 0;
+        for caps in Regex::new(&pattern)?.find_all(&chars, &mut |n| env.charge(n))? {
+            let (start, end) = caps[0].unwrap();
+            // An empty match at the very start or end doesn't split anything.
+            if start == end && (start == 0 || start == chars.len()) {
+                continue;
+            }
+            pieces.push(chars[last..start].iter().collect::<String>());
+            last = end;
+        }
+        pieces.push(chars[last..].iter().collect::<String>());
+        Ok(pieces)
+    });
+}
+
+fn slice(chars: &[char], range: Option<(usize, usize)>) -> JObject {
+    match range {
+        Some((start, end)) => JObject::String(chars[start..end].iter().collect()),
+        None => JObject::Null,
+    }
+}
+
+/// `{"0": whole match, "1": first group, ..., "name": named group}`, with
+/// `null` for groups that didn't take part in the match.
+fn captures_map(regex: &Regex, chars: &[char], caps: &Captures) -> JObject {
+    let mut map = OrderedMap::new();
+    for (i, range) in caps.iter().enumerate() {
+        map.insert(i.to_string(), Box::new(slice(chars, *range)));
+    }
+    for (name, slot) in regex.names() {
+        map.insert(name.clone(), Box::new(slice(chars, caps[*slot])));
+    }
+    JObject::Map(map)
+}
+
+/// Appends `template` with its group references filled in.
+fn expand(
+    out: &mut String,
+    template: &str,
+    regex: &Regex,
+    chars: &[char],
+    caps: &Captures,
+) -> Result<(), Error> {
+    let bad = |what: &str| {
+        Err(Error::new(
+            "bad-replacement",
+            format!("{} in {:?}", what, template),
+        ))
+    };
+    let mut rest = template;
+    while let Some(at) = rest.find('$') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        // `${name}`, or `$name` running to the end of the word.
+        let (name, used) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(close) => (&braced[..close], close + 2),
+                None => return bad("missing '}'"),
+            },
+            None => {
+                let len = rest.len()
+                    - rest
+                        .trim_start_matches(|c: char| c.is_alphanumeric() || c == '_')
+                        .len();
+                (&rest[..len], len)
+            }
+        };
+        if name.is_empty() {
+            return bad("'$' without a group");
+        }
+        let slot = match name.parse::<usize>() {
+            Ok(n) => Some(n),
+            Err(_) => regex
+                .names()
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|&(_, s)| s),
+        };
+        match slot.filter(|&s| s <= regex.groups()) {
+            Some(slot) => {
+                if let Some((start, end)) = caps[slot] {
+                    out.extend(&chars[start..end]);
+                }
+            }
+            None => return bad("unknown group"),
+        }
+        rest = &rest[used..];
+    }
+    out.push_str(rest);
+    Ok(())
+}
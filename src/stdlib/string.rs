@@ -0,0 +1,173 @@
+use crate::{
+    args::{convert, min_arity, typed},
+    error::Error,
+    eval::{eval, Environment},
+    json::JObject,
+};
+
+use super::array::{clamp, concat};
+
+// Positions and lengths count characters, not bytes.
+pub fn load_mod(env: &mut Environment) {
+    env.insert_builtin("concat", concat);
+
+    env.insert_typed("length", |_env, (s,): (String,)| {
+        Ok(s.chars().count() as i64)
+    });
+
+    // (substring s start) or (substring s start end), clamped to s
+    env.insert_builtin("substring", |env, args| {
+        let (s, start, end) = match args.len() {
+            2 => {
+                let (s, start): (String, i64) = typed(env, "substring", args)?;
+                (s, start, i64::MAX)
+            }
+            _ => typed::<(String, i64, i64)>(env, "substring", args)?,
+        };
+        let len = s.chars().count();
+        let start = clamp(start, len);
+        let end = clamp(end, len).max(start);
+        Ok(JObject::String(
+            s.chars().skip(start).take(end - start).collect(),
+        ))
+    });
+
+    // (split s separator). An empty separator splits into characters.
+    env.insert_typed("split", |_env, (s, sep): (String, String)| {
+        if sep.is_empty() {
+            return Ok(chars(&s));
+        }
+        Ok(s.split(&sep).map(str::to_string).collect())
+    });
+
+    // (join list separator). Elements that aren't strings are written as
+    // JSON.
+    env.insert_typed("join", |_env, (list, sep): (Vec<JObject>, String)| {
+        Ok(list.iter().map(plain).collect::<Vec<String>>().join(&sep))
+    });
+
+    env.insert_typed("trim", |_env, (s,): (String,)| Ok(s.trim().to_string()));
+    env.insert_typed("upper", |_env, (s,): (String,)| Ok(s.to_uppercase()));
+    env.insert_typed("lower", |_env, (s,): (String,)| Ok(s.to_lowercase()));
+
+    env.insert_typed("starts-with", |_env, (s, prefix): (String, String)| {
+        Ok(s.starts_with(&prefix))
+    });
+
+    env.insert_typed("ends-with", |_env, (s, suffix): (String, String)| {
+        Ok(s.ends_with(&suffix))
+    });
+
+    env.insert_typed("contains", |_env, (s, part): (String, String)| {
+        Ok(s.contains(&part))
+    });
+
+    // (replace s from to) replaces every occurrence
+    env.insert_typed(
+        "replace",
+        |_env, (s, from, to): (String, String, String)| {
+            if from.is_empty() {
+                return Err(Error::new("bad-argument", "replace: nothing to replace"));
+            }
+            Ok(s.replace(&from, &to))
+        },
+    );
+
+    // (index-of s part) is the position of the first match, or null
+    env.insert_typed("index-of", |_env, (s, part): (String, String)| {
+        Ok(s.find(&part).map(|i| s[..i].chars().count() as i64))
+    });
+
+    // (pad-left s width) or (pad-left s width fill), fill defaulting to " "
+    env.insert_builtin("pad-left", |env, args| {
+        let (s, padding) = pad("pad-left", env, args)?;
+        Ok(JObject::String(padding + &s))
+    });
+
+    env.insert_builtin("pad-right", |env, args| {
+        let (s, padding) = pad("pad-right", env, args)?;
+        Ok(JObject::String(s + &padding))
+    });
+
+    env.insert_typed("chars", |_env, (s,): (String,)| Ok(chars(&s)));
+
+    env.insert_typed("repeat", |env, (s, n): (String, i64)| {
+        let n = usize::try_from(n)
+            .map_err(|_| Error::new("bad-argument", format!("repeat: {} < 0", n)))?;
+        env.allocate(s.chars().count().saturating_mul(n))?;
+        Ok(s.repeat(n))
+    });
+
+    // (format "{} is {}" a b). `{{` and `}}` are literal braces.
+    env.insert_builtin("format", |env, args| {
+        min_arity(args, 1)?;
+        let template: String = convert("format", 0, &eval(env, &args[0])?)?;
+        let mut values = Vec::new();
+        for arg in &args[1..] {
+            values.push(eval(env, arg)?);
+        }
+        format(&template, &values).map(JObject::String)
+    });
+}
+
+fn chars(s: &str) -> Vec<String> {
+    s.chars().map(String::from).collect()
+}
+
+/// Strings as they are, anything else as JSON.
+fn plain(o: &JObject) -> String {
+    match o {
+        JObject::String(s) => s.clone(),
+        o => o.to_string(),
+    }
+}
+
+/// Reads the arguments of `pad-left` or `pad-right`, returning the string
+/// and the padding that brings it up to the width.
+fn pad(fname: &str, env: &mut Environment, args: &[JObject]) -> Result<(String, String), Error> {
+    let (s, width, fill) = match args.len() {
+        2 => {
+            let (s, width): (String, i64) = typed(env, fname, args)?;
+            (s, width, " ".to_string())
+        }
+        _ => typed::<(String, i64, String)>(env, fname, args)?,
+    };
+    let mut fill_chars = fill.chars();
+    let (Some(fill), None) = (fill_chars.next(), fill_chars.next()) else {
+        return Err(Error::new(
+            "bad-argument",
+            format!("{}: fill should be one character, not {:?}", fname, fill),
+        ));
+    };
+    let missing = clamp(width, usize::MAX).saturating_sub(s.chars().count());
+    env.allocate(missing)?;
+    Ok((s, std::iter::repeat_n(fill, missing).collect()))
+}
+
+fn format(template: &str, values: &[JObject]) -> Result<String, Error> {
+    let bad = |message: String| Err(Error::new("bad-format", message));
+    let mut out = String::new();
+    let mut values = values.iter();
+    let mut chars = template.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                out.push(ch);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                match values.next() {
+                    Some(value) => out.push_str(&plain(value)),
+                    None => return bad(format!("not enough values for {:?}", template)),
+                }
+            }
+            ('{' | '}', _) => return bad(format!("unmatched {:?} in {:?}", ch, template)),
+            _ => out.push(ch),
+        }
+    }
+    if values.next().is_some() {
+        return bad(format!("too many values for {:?}", template));
+    }
+    Ok(out)
+}
@@ -1,7 +1,8 @@
 use super::*;
+use crate::error::Error;
 use crate::eval::eval;
 use crate::eval::Environment;
-use crate::json::{self, JObject};
+use crate::json::{self, JObject, ToJObject};
 
 #[test]
 fn test_env() {
@@ -9,7 +10,7 @@ fn test_env() {
     env.symbols.insert("x".to_string(), JObject::Number(3));
 
     let expr = json::parse("\"x\"");
-    let result = eval(env, &expr);
+    let result = eval(env, &expr).unwrap();
 
     assert_eq!(result, JObject::Number(3));
 }
@@ -36,7 +37,7 @@ fn test_env() {
 fn test_builtin_def() {
     let env = &mut Environment::init();
     stdlib::load_mod(env);
-    eval(env, &json::parse(r#"["def", "e", 3]")"#));
+    eval(env, &json::parse(r#"["def", "e", 3]")"#)).unwrap();
     assert_eq!(env.symbols.get("e"), Some(&JObject::Number(3)));
 }
 
@@ -60,16 +61,29 @@ fn test_quasiquote() {
     stdlib::load_mod(env);
 
     let o = json::parse(cmd);
-    let new_o = eval(env, &o);
+    let new_o = eval(env, &o).unwrap();
     assert!(new_o == json::parse(r#"[1,3,2]"#))
 }
 
+#[test]
+fn malformed_unquote_is_an_error() {
+    let env = &mut Environment::init();
+    stdlib::load_mod(env);
+    for source in [
+        r#"["quasiquote", [["splice-unquote"]]]"#,
+        r#"["quasiquote", [1, ["splice-unquote", 1, 2]]]"#,
+        r#"["quasiquote", ["unquote"]]"#,
+    ] {
+        assert_eq!(error_kind(env, source), "bad-arity", "{}", source);
+    }
+}
+
 #[test]
 fn test_func_literal() {
     let env = &mut Environment::init();
     stdlib::load_mod(env);
 
-    let prog = eval(env, &json::parse(r#"["f", [], 123]"#));
+    let prog = eval(env, &json::parse(r#"["f", [], 123]"#)).unwrap();
     assert_eq!(prog, JObject::new_func(vec![], JObject::Number(123)),);
 }
 
@@ -97,7 +111,7 @@ fn test_func_as_list() {
     let func = JObject::new_func(vec!["x"], JObject::String("x".to_string()));
     let list = JObject::List(vec![func, JObject::Number(42)]);
 
-    let result = eval(env, &list);
+    let result = eval(env, &list).unwrap();
 
     assert_eq!(result, JObject::Number(42))
 }
@@ -116,7 +130,7 @@ fn test_func_call() {
     let o = json::parse("[\"x\"]");
     assert_eq!(o, JObject::List(vec![JObject::String("x".to_string())]));
 
-    let res = eval(env, &o);
+    let res = eval(env, &o).unwrap();
     assert_eq!(res, JObject::String("a funmtciun wer called".to_string()));
 }
 
@@ -133,7 +147,7 @@ fn call_function_in_function_body() {
     );
 
     let o = json::parse(r#"["id", ["one"]]"#);
-    let res = eval(env, &o);
+    let res = eval(env, &o).unwrap();
 
     assert_eq!(res, JObject::Number(1));
 }
@@ -152,7 +166,7 @@ fn macro_simple() {
         JObject::List(vec![JObject::String("return-22".to_string())])
     );
 
-    let res = eval(env, &o);
+    let res = eval(env, &o).unwrap();
     assert_eq!(res, JObject::Number(22));
 }
 
@@ -215,3 +229,1922 @@ fn test_parse_list() {
         ])
     );
 }
+
+// scope
+
+#[test]
+fn parameters_are_local() {
+    let env = &mut Environment::init();
+    stdlib::load_mod(env);
+
+    eval(env, &json::parse(r#"["def", "id", ["f", ["x"], "x"]]"#)).unwrap();
+    assert_eq!(
+        eval(env, &json::parse(r#"["id", 1]"#)),
+        Ok(JObject::Number(1))
+    );
+    assert_eq!(env.symbols.get("x"), None);
+    assert_eq!(env.lookup("x"), None);
+}
+
+#[test]
+fn closures_capture_their_scope() {
+    let env = &mut Environment::init();
+    stdlib::load_mod(env);
+
+    eval(
+        env,
+        &json::parse(r#"["def", "const", ["f", ["x"], ["f", ["y"], "x"]]]"#),
+    )
+    .unwrap();
+    eval(env, &json::parse(r#"["def", "one", ["const", 1]]"#)).unwrap();
+    eval(env, &json::parse(r#"["def", "two", ["const", 2]]"#)).unwrap();
+
+    assert_eq!(
+        eval(env, &json::parse(r#"["one", 5]"#)),
+        Ok(JObject::Number(1))
+    );
+    assert_eq!(
+        eval(env, &json::parse(r#"["two", 5]"#)),
+        Ok(JObject::Number(2))
+    );
+}
+
+#[test]
+fn inner_parameters_shadow_outer() {
+    let env = &mut Environment::init();
+    stdlib::load_mod(env);
+
+    eval(env, &json::parse(r#"["def", "inner", ["f", ["x"], "x"]]"#)).unwrap();
+    eval(
+        env,
+        &json::parse(r#"["def", "outer", ["f", ["x"], ["program", ["inner", 2], "x"]]]"#),
+    )
+    .unwrap();
+
+    assert_eq!(
+        eval(env, &json::parse(r#"["outer", 1]"#)),
+        Ok(JObject::Number(1))
+    );
+}
+
+#[test]
+fn parse_errors_have_positions() {
+    let err = json::try_parse("[1,\n  2 3]").unwrap_err();
+    assert_eq!(err.expected, "',' or ']'");
+    assert_eq!((err.offset, err.line, err.column), (8, 2, 5));
+    assert_eq!(err.snippet, "  2 3]\n    ^");
+
+    let err = json::try_parse(r#"{"a" 1}"#).unwrap_err();
+    assert_eq!(err.expected, "':'");
+    assert_eq!(err.column, 6);
+
+    let err = json::try_parse("[\"é\"] x").unwrap_err();
+    assert_eq!(err.expected, "end of input");
+    assert_eq!((err.offset, err.column), (7, 7));
+}
+
+#[test]
+fn parse_error_on_unterminated_input() {
+    assert!(json::try_parse("[1, 2").is_err());
+    assert!(json::try_parse(r#""abc"#).is_err());
+    assert!(json::try_parse("").is_err());
+    assert_eq!(json::parse("[1, 2"), JObject::Null);
+}
+
+#[test]
+fn parse_error_on_deep_nesting() {
+    let deepest = format!(
+        "{}{}",
+        "[".repeat(json::MAX_NESTING),
+        "]".repeat(json::MAX_NESTING)
+    );
+    assert_eq!(json::try_parse(&deepest).unwrap().to_string(), deepest);
+
+    let err = json::try_parse(&"[".repeat(200_000)).unwrap_err();
+    assert_eq!(err.expected, "at most 128 levels of nesting");
+    assert_eq!(err.column, json::MAX_NESTING + 1);
+    let err = json::try_parse(&r#"{"a": "#.repeat(200_000)).unwrap_err();
+    assert_eq!(err.expected, "at most 128 levels of nesting");
+
+    // anything that parses can also be run, even on a test thread's stack
+    let env = &mut env_with(&["std::math"]);
+    let n = json::MAX_NESTING - 1;
+    let sum = format!("{}0{}", r#"["+", 1, "#.repeat(n), "]".repeat(n));
+    assert_eq!(run(env, &sum), Ok(JObject::Number(n as i64)));
+}
+
+#[test]
+fn parse_string_escapes() {
+    assert_eq!(
+        json::parse(r#""a\"b\\c\/d\n\t\r\b\f""#),
+        JObject::String("a\"b\\c/d\n\t\r\u{8}\u{c}".to_string())
+    );
+    assert_eq!(
+        json::parse(r#""\u00e9\u4E2D\ud83d\ude00""#),
+        JObject::String("é中😀".to_string())
+    );
+    assert!(json::try_parse(r#""\x""#).is_err());
+    assert!(json::try_parse(r#""\ud83d""#).is_err());
+    assert!(json::try_parse(r#""\ude00""#).is_err());
+    assert!(json::try_parse("\"a\nb\"").is_err());
+}
+
+#[test]
+fn strings_round_trip() {
+    let s = JObject::String("say \"hi\"\\\n\t\u{1}é😀".to_string());
+    assert_eq!(s.to_string(), r#""say \"hi\"\\\n\t\u0001é😀""#);
+    assert_eq!(json::parse(&s.to_string()), s);
+}
+
+#[test]
+fn parse_numbers() {
+    assert_eq!(json::parse("-1"), JObject::Number(-1));
+    assert_eq!(json::parse("0"), JObject::Number(0));
+    assert_eq!(json::parse("3.5"), JObject::Float(3.5));
+    assert_eq!(json::parse("-0.25"), JObject::Float(-0.25));
+    assert_eq!(json::parse("1e9"), JObject::Float(1e9));
+    assert_eq!(json::parse("2.5E-3"), JObject::Float(2.5e-3));
+    assert_eq!(
+        json::parse("9223372036854775808"),
+        JObject::Float(9223372036854775808.0)
+    );
+    assert!(json::try_parse("01").is_err());
+    assert!(json::try_parse("1.").is_err());
+    assert!(json::try_parse("-").is_err());
+    assert!(json::try_parse("1e").is_err());
+    assert!(json::try_parse("1e999").is_err());
+}
+
+#[test]
+fn floats_round_trip() {
+    for f in [3.0, -0.5, 1e300, 2.5e-8] {
+        let o = JObject::Float(f);
+        assert_eq!(json::parse(&o.to_string()), o);
+    }
+    assert_eq!(JObject::Float(3.0).to_string(), "3.0");
+    assert_eq!(JObject::Float(1.0).typename(), "Float");
+}
+
+#[test]
+fn numeric_promotion() {
+    use json::{promote, Numbers};
+
+    assert_eq!(
+        promote(&JObject::Number(1), &JObject::Number(2)),
+        Some(Numbers::Ints(1, 2))
+    );
+    assert_eq!(
+        promote(&JObject::Number(1), &JObject::Float(2.5)),
+        Some(Numbers::Floats(1.0, 2.5))
+    );
+    assert_eq!(promote(&JObject::Number(1), &JObject::Null), None);
+}
+
+// std::math
+
+/// An environment with the core builtins and `modules` imported.
+fn env_with(modules: &[&str]) -> Environment {
+    let mut env = Environment::init();
+    stdlib::load_mod(&mut env);
+    for module in modules {
+        stdlib::import_builtin_library(&mut env, module).unwrap();
+    }
+    env
+}
+
+fn run(env: &mut Environment, program: &str) -> Result<JObject, Error> {
+    eval(env, &json::parse(program))
+}
+
+/// Asserts that `source` evaluates to the JSON `expected`, keys in order.
+fn check(env: &mut Environment, source: &str, expected: &str) {
+    let res = run(env, source).unwrap();
+    assert_eq!(
+        res.to_string(),
+        json::parse(expected).to_string(),
+        "{}",
+        source
+    );
+}
+
+/// The kind of error `source` fails with.
+fn error_kind(env: &mut Environment, source: &str) -> String {
+    run(env, source).unwrap_err().kind().to_string()
+}
+
+#[test]
+fn math_arithmetic() {
+    let env = &mut env_with(&["std::math"]);
+    assert_eq!(run(env, r#"["+", 1, 2, 3]"#), Ok(JObject::Number(6)));
+    assert_eq!(run(env, r#"["+"]"#), Ok(JObject::Number(0)));
+    assert_eq!(run(env, r#"["-", 5]"#), Ok(JObject::Number(-5)));
+    assert_eq!(run(env, r#"["-", 10, 1, 2]"#), Ok(JObject::Number(7)));
+    assert_eq!(run(env, r#"["*", 2, 1.5]"#), Ok(JObject::Float(3.0)));
+    assert_eq!(run(env, r#"["/", 7, 2]"#), Ok(JObject::Number(3)));
+    assert_eq!(run(env, r#"["/", 7.0, 2]"#), Ok(JObject::Float(3.5)));
+    assert_eq!(run(env, r#"["mod", -7, 3]"#), Ok(JObject::Number(-1)));
+    assert_eq!(run(env, r#"["abs", -2.5]"#), Ok(JObject::Float(2.5)));
+    assert_eq!(run(env, r#"["min", 3, 1.5, 2]"#), Ok(JObject::Float(1.5)));
+    assert_eq!(run(env, r#"["max", 3, 1.5, 2]"#), Ok(JObject::Number(3)));
+}
+
+#[test]
+fn math_comparison() {
+    let env = &mut env_with(&["std::math"]);
+    assert_eq!(run(env, r#"["<", 1, 2, 3]"#), Ok(JObject::Bool(true)));
+    assert_eq!(run(env, r#"["<", 1, 3, 2]"#), Ok(JObject::Bool(false)));
+    assert_eq!(run(env, r#"[">=", 2, 2.0, 1]"#), Ok(JObject::Bool(true)));
+    assert_eq!(run(env, r#"["=", 1, 1.0]"#), Ok(JObject::Bool(true)));
+    assert_eq!(run(env, r#"["!=", 1, 2]"#), Ok(JObject::Bool(true)));
+}
+
+#[test]
+fn math_errors() {
+    let env = &mut env_with(&["std::math"]);
+    assert_eq!(
+        error_kind(env, r#"["+", 9223372036854775807, 1]"#),
+        "overflow"
+    );
+    assert_eq!(
+        error_kind(env, r#"["abs", -9223372036854775808]"#),
+        "overflow"
+    );
+    assert_eq!(error_kind(env, r#"["/", 1, 0]"#), "division-by-zero");
+    assert_eq!(error_kind(env, r#"["*", 1e300, 1e300]"#), "overflow");
+    assert_eq!(
+        error_kind(env, r#"["<", 1, ["quote", "a"]]"#),
+        "not-a-number"
+    );
+}
+
+// errors
+
+#[test]
+fn errors_propagate_out_of_eval() {
+    let env = &mut env_with(&["std::math", "std::array"]);
+
+    let err = run(
+        env,
+        r#"["map", ["f", ["x"], ["head", "x", "x"]], ["quote", [1, 2]]]"#,
+    );
+    assert_eq!(err, Err(Error::arity(2, 1)));
+    assert_eq!(
+        run(env, r#"["throw", ["quote", {"code": 7}]]"#),
+        Err(Error::Thrown(json::parse(r#"{"code": 7}"#)))
+    );
+}
+
+#[test]
+fn try_catch_finally() {
+    let env = &mut env_with(&["std::math"]);
+
+    assert_eq!(
+        run(
+            env,
+            r#"["try", ["throw", 1], ["catch", "e", ["+", "e", 1]]]"#
+        ),
+        Ok(JObject::Number(2))
+    );
+    assert_eq!(
+        run(env, r#"["try", ["/", 1, 0], ["catch", "e", "e"]]"#),
+        Ok(json::parse(r#"["error", "division-by-zero", "1"]"#))
+    );
+    assert_eq!(
+        run(env, r#"["try", 5, ["catch", "e", 0]]"#),
+        Ok(JObject::Number(5))
+    );
+
+    run(
+        env,
+        r#"["try", ["throw", 1], ["finally", ["def", "cleaned", true]]]"#,
+    )
+    .unwrap_err();
+    assert_eq!(env.symbols.get("cleaned"), Some(&JObject::Bool(true)));
+    assert_eq!(env.lookup("e"), None);
+}
+
+#[test]
+fn bad_definitions_are_errors() {
+    let env = &mut Environment::init();
+    stdlib::load_mod(env);
+
+    assert_eq!(error_kind(env, r#"["def", 1, 2]"#), "bad-name");
+    assert_eq!(error_kind(env, r#"["f", [1], 2]"#), "bad-parameter");
+    assert_eq!(error_kind(env, r#"["f", "x", 2]"#), "bad-parameter");
+    assert_eq!(error_kind(env, r#"["macro", [["x"]], 2]"#), "bad-parameter");
+    assert_eq!(
+        run(env, r#"["try", ["def", [], 1], ["catch", "e", 0]]"#),
+        Ok(JObject::Number(0))
+    );
+}
+
+// tail calls
+
+#[test]
+fn self_recursion_runs_in_constant_stack() {
+    let env = &mut env_with(&["std::math", "std::logic"]);
+
+    run(
+        env,
+        r#"["def", "count", ["f", ["n", "acc"],
+            ["if", ["=", "n", 0],
+                "acc",
+                ["program", ["count", ["-", "n", 1], ["+", "acc", 1]]]]]]"#,
+    )
+    .unwrap();
+    assert_eq!(
+        run(env, r#"["count", 30000, 0]"#),
+        Ok(JObject::Number(30000))
+    );
+}
+
+#[test]
+fn mutual_recursion_runs_in_constant_stack() {
+    let env = &mut env_with(&["std::math", "std::logic"]);
+
+    run(
+        env,
+        r#"["def", "even?", ["f", ["n"], ["if", ["=", "n", 0], true, ["odd?", ["-", "n", 1]]]]]"#,
+    )
+    .unwrap();
+    run(
+        env,
+        r#"["def", "odd?", ["f", ["n"], ["if", ["=", "n", 0], false, ["even?", ["-", "n", 1]]]]]"#,
+    )
+    .unwrap();
+    assert_eq!(run(env, r#"["even?", 30001]"#), Ok(JObject::Bool(false)));
+    assert!(env.scope.is_none());
+}
+
+// limits
+
+fn looping_env(limits: eval::Limits) -> Environment {
+    let mut env = env_with(&["std::math"]).with_limits(limits);
+    stdlib::import_builtin_library(&mut env, "std::logic").unwrap();
+    run(
+        &mut env,
+        r#"["def", "deep", ["f", ["n"], ["if", ["=", "n", 0], 0, ["+", 1, ["deep", ["-", "n", 1]]]]]]"#,
+    )
+    .unwrap();
+    run(&mut env, r#"["def", "spin", ["f", [], ["spin"]]]"#).unwrap();
+    env
+}
+
+#[test]
+fn depth_limit() {
+    let env = &mut looping_env(eval::Limits {
+        max_depth: Some(200),
+        ..Default::default()
+    });
+    assert_eq!(run(env, r#"["deep", 10]"#), Ok(JObject::Number(10)));
+    let err = run(env, r#"["try", ["deep", 1000], ["catch", "e", "e"]]"#).unwrap_err();
+    assert_eq!(err.kind(), "limit-exceeded");
+    assert_eq!(env.depth, 0);
+}
+
+#[test]
+fn reset_after_a_panic() {
+    let env = &mut looping_env(eval::Limits {
+        max_depth: Some(200),
+        ..Default::default()
+    });
+    env.insert_builtin("boom", |_env, _args| panic!("boom"));
+    let program = json::parse(r#"["+", 1, ["+", 1, ["boom"]]]"#);
+    let caught = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| eval(env, &program)));
+    assert!(caught.is_err());
+    assert!(env.depth > 0);
+
+    env.reset_run_state();
+    assert_eq!((env.depth, env.steps), (0, 0));
+    assert_eq!(run(env, r#"["deep", 150]"#), Ok(JObject::Number(150)));
+}
+
+#[test]
+fn step_limit() {
+    let env = &mut looping_env(eval::Limits {
+        max_steps: Some(10_000),
+        ..Default::default()
+    });
+    assert_eq!(
+        run(env, r#"["spin"]"#).unwrap_err().kind(),
+        "limit-exceeded"
+    );
+    assert!(env.steps > 10_000);
+}
+
+#[test]
+fn built_elements_count_as_steps() {
+    let env = &mut Environment::builder()
+        .limits(eval::Limits {
+            max_steps: Some(10_000),
+            ..Default::default()
+        })
+        .build();
+    run(env, r#"["import", "std::array"]"#).unwrap();
+    assert_eq!(
+        run(env, r#"["len", ["range", 5000]]"#),
+        Ok(JObject::Number(5000))
+    );
+    assert_eq!(
+        run(env, r#"["len", ["range", 20000]]"#).unwrap_err().kind(),
+        "limit-exceeded"
+    );
+}
+
+#[test]
+fn growing_a_value_costs_its_length() {
+    let fresh = || {
+        let mut env = Environment::builder()
+            .limits(eval::Limits {
+                max_steps: Some(10_000),
+                ..Default::default()
+            })
+            .build();
+        run(&mut env, r#"["import", "std::array"]"#).unwrap();
+        env
+    };
+    // each doubling is cheap to evaluate but costs its new length, so
+    // fourteen of them run past the limit
+    for (start, step) in [
+        (r#"["range", 1]"#, r#"["concat", "x", "x"]"#),
+        (r#""x""#, r#"["concat", "x", "x"]"#),
+        (r#"["range", 1]"#, r#"["flatten", ["zip", "x", "x"]]"#),
+        (
+            r#"["range", 1]"#,
+            r#"["flat-map", ["f", ["_"], "x"], ["range", 2]]"#,
+        ),
+    ] {
+        let doubling = |times| {
+            format!(
+                r#"["do", ["def", "x", {}]{}]"#,
+                start,
+                format!(r#", ["def", "x", {}]"#, step).repeat(times)
+            )
+        };
+        assert!(run(&mut fresh(), &doubling(4)).is_ok(), "{}", step);
+        assert_eq!(
+            error_kind(&mut fresh(), &doubling(14)),
+            "limit-exceeded",
+            "{}",
+            step
+        );
+    }
+    let appends = format!(
+        r#"["do", ["def", "x", ["range", 200]]{}]"#,
+        r#", ["def", "x", ["append", "x", 0]]"#.repeat(100)
+    );
+    assert_eq!(error_kind(&mut fresh(), &appends), "limit-exceeded");
+}
+
+#[test]
+fn values_cannot_nest_too_deeply() {
+    let env = &mut looping_env(eval::Limits {
+        max_depth: Some(1000),
+        ..Default::default()
+    });
+    stdlib::import_builtin_library(env, "std::array").unwrap();
+    // a tail call, so only the value gets deeper, not the stack
+    run(
+        env,
+        r#"["def", "wrap", ["f", ["x", "n"], ["if", ["=", "n", 0], "x", ["wrap", ["append", [], "x"], ["-", "n", 1]]]]]"#,
+    )
+    .unwrap();
+    check(env, r#"["wrap", 1, 3]"#, "[[[1]]]");
+    assert!(run(env, r#"["wrap", 1, 100]"#).is_ok());
+    assert_eq!(error_kind(env, r#"["wrap", 1, 100000]"#), "limit-exceeded");
+    run(
+        env,
+        r#"["def", "box", ["f", ["x", "n"], ["if", ["=", "n", 0], "x", ["box", {"a": "x"}, ["-", "n", 1]]]]]"#,
+    )
+    .unwrap();
+    assert_eq!(error_kind(env, r#"["box", 1, 100000]"#), "limit-exceeded");
+}
+
+#[test]
+fn deadline_limit() {
+    let env = &mut looping_env(eval::Limits {
+        deadline: Some(std::time::Instant::now()),
+        ..Default::default()
+    });
+    assert_eq!(
+        run(env, r#"["spin"]"#).unwrap_err().kind(),
+        "limit-exceeded"
+    );
+}
+
+// capabilities
+
+#[test]
+fn pure_environment() {
+    let env = &mut Environment::builder().pure().build();
+
+    assert!(!env.builtins.contains_key("crash"));
+    assert!(env.builtins.contains_key("def"));
+    assert_eq!(
+        run(env, r#"["import", "std::math"]"#),
+        Ok("ok".to_jobject())
+    );
+    assert_eq!(
+        run(env, r#"["import", "std::io"]"#).unwrap_err().kind(),
+        "import-denied"
+    );
+    assert!(!env.builtins.contains_key("println"));
+}
+
+#[test]
+fn builder_allow_and_deny() {
+    let env = &mut Environment::builder()
+        .allow_module("std::array")
+        .deny_builtin("len")
+        .deny_builtin("def")
+        .build();
+
+    assert!(!env.builtins.contains_key("def"));
+    run(env, r#"["import", "std::array"]"#).unwrap();
+    assert!(env.builtins.contains_key("head"));
+    assert!(!env.builtins.contains_key("len"));
+    assert!(run(env, r#"["import", "std::math"]"#).is_err());
+}
+
+// native closures
+
+#[test]
+fn builtins_can_capture_host_state() {
+    let env = &mut Environment::init();
+    let greeting = "hello".to_string();
+    env.insert_builtin("greeting", move |_env, _args| {
+        Ok(JObject::String(greeting.clone()))
+    });
+
+    let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+    let seen = calls.clone();
+    env.insert_builtin_mut("next", move |_env, _args| {
+        seen.set(seen.get() + 1);
+        Ok(JObject::Number(seen.get()))
+    });
+
+    assert_eq!(run(env, r#"["greeting"]"#), Ok("hello".to_jobject()));
+    run(env, r#"["next"]"#).unwrap();
+    assert_eq!(run(env, r#"["next"]"#), Ok(JObject::Number(2)));
+    assert_eq!(calls.get(), 2);
+}
+
+#[test]
+fn reentrant_mut_builtin_is_an_error() {
+    let env = &mut Environment::init();
+    env.insert_builtin_mut("again", |env, args| match args.first() {
+        Some(arg) => eval(env, arg),
+        None => Ok(JObject::Null),
+    });
+
+    assert_eq!(run(env, r#"["again"]"#), Ok(JObject::Null));
+    assert_eq!(
+        run(env, r#"["again", ["again"]]"#).unwrap_err().kind(),
+        "reentrant-builtin"
+    );
+}
+
+// typed builtins
+
+#[test]
+fn typed_builtin_checks_arity_and_types() {
+    let env = &mut Environment::init();
+    stdlib::load_mod(env);
+    env.insert_typed("repeat", |_env, (x, n): (JObject, i64)| {
+        Ok(vec![x; n as usize])
+    });
+
+    assert_eq!(
+        run(env, r#"["repeat", true, 2]"#),
+        Ok(json::parse("[true, true]"))
+    );
+    assert_eq!(run(env, r#"["repeat", true]"#), Err(Error::arity(1, 2)));
+    assert_eq!(
+        run(env, r#"["repeat", 1, ["quote", "x"]]"#),
+        Err(Error::new(
+            "bad-type",
+            r#"repeat: argument 2 should be Number, not String "x""#
+        ))
+    );
+}
+
+#[test]
+fn map_calls_functions_with_values() {
+    let env = &mut env_with(&["std::array"]);
+
+    assert_eq!(
+        run(
+            env,
+            r#"["map", ["f", ["x"], ["len", "x"]], ["quote", [["a"], []]]]"#
+        ),
+        Ok(json::parse("[1, 0]"))
+    );
+    assert_eq!(
+        run(env, r#"["map", "->string", ["quote", [[1, 2], "x"]]]"#),
+        Ok(json::parse(r#"["[1,2]", "\"x\""]"#))
+    );
+    assert_eq!(
+        run(env, r#"["map", 1, []]"#).unwrap_err().kind(),
+        "bad-type"
+    );
+}
+
+// conversions
+
+#[test]
+fn std_types_round_trip() {
+    use json::FromJObject;
+    use std::collections::HashMap;
+
+    let counts = HashMap::from([("a".to_string(), 1i64), ("b".to_string(), 2)]);
+    assert_eq!(
+        HashMap::<String, i64>::from_jobject(&counts.to_jobject()),
+        Some(counts)
+    );
+    assert_eq!(
+        (1i64, "x".to_string(), 2.5f64).to_jobject(),
+        json::parse(r#"[1, "x", 2.5]"#)
+    );
+    assert_eq!(
+        <(i64, String)>::from_jobject(&json::parse(r#"[1, "x"]"#)),
+        Some((1, "x".to_string()))
+    );
+    assert_eq!(
+        vec![Some(1i64), None].to_jobject(),
+        json::parse("[1, null]")
+    );
+    assert_eq!(
+        Vec::<Option<i64>>::from_jobject(&json::parse("[1, null]")),
+        Some(vec![Some(1), None])
+    );
+    assert_eq!(Vec::<i64>::from_jobject(&json::parse("[1, true]")), None);
+}
+
+#[derive(Debug, PartialEq, json::ToJObject, json::FromJObject)]
+struct Package {
+    name: String,
+    r#type: Option<String>,
+    version: (i64, i64),
+    tags: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, json::ToJObject, json::FromJObject)]
+struct Meters(f64);
+
+#[derive(Debug, PartialEq, json::ToJObject, json::FromJObject)]
+enum Shape {
+    Empty,
+    Circle(Meters),
+    Rect { w: i64, h: i64 },
+}
+
+#[test]
+fn derived_struct_round_trips() {
+    use json::FromJObject;
+
+    let p = Package {
+        name: "jl".to_string(),
+        r#type: None,
+        version: (0, 1),
+        tags: vec!["lisp".to_string()],
+    };
+    let o = p.to_jobject();
+    assert_eq!(o.get("name"), Some(&"jl".to_jobject()));
+    assert_eq!(o.get("type"), Some(&JObject::Null));
+    assert_eq!(Package::from_jobject(&o), Some(p));
+
+    let missing_optional = json::parse(r#"{"name": "x", "version": [1, 2], "tags": []}"#);
+    assert!(Package::from_jobject(&missing_optional).is_some());
+    assert_eq!(
+        Package::from_jobject(&json::parse(r#"{"name": "x"}"#)),
+        None
+    );
+}
+
+#[test]
+fn derived_enum_uses_tagged_forms() {
+    use json::FromJObject;
+
+    let shapes = vec![
+        Shape::Empty,
+        Shape::Circle(Meters(1.5)),
+        Shape::Rect { w: 2, h: 3 },
+    ];
+    let o = shapes.to_jobject();
+    assert_eq!(
+        o,
+        json::parse(r#"["Empty", ["Circle", 1.5], ["Rect", {"w": 2, "h": 3}]]"#)
+    );
+    assert_eq!(Vec::<Shape>::from_jobject(&o), Some(shapes));
+    assert_eq!(Shape::from_jobject(&json::parse(r#"["Circle"]"#)), None);
+    assert_eq!(Shape::typename(), "Shape");
+}
+
+// ordered maps
+
+#[test]
+fn maps_keep_insertion_order() {
+    let env = &mut env_with(&["std::object"]);
+
+    let source = r#"{"zeta": 1, "alpha": 2, "mid": 3}"#;
+    let parsed = json::parse(source);
+    let keys = |o: &JObject| match o {
+        JObject::Map(m) => m.keys().cloned().collect::<Vec<String>>(),
+        _ => vec![],
+    };
+    assert_eq!(keys(&parsed), ["zeta", "alpha", "mid"]);
+    assert_eq!(keys(&eval(env, &parsed).unwrap()), ["zeta", "alpha", "mid"]);
+
+    let inserted = run(env, &format!(r#"["insert", {}, "beta", 4]"#, source)).unwrap();
+    assert_eq!(keys(&inserted), ["zeta", "alpha", "mid", "beta"]);
+    let replaced = run(env, &format!(r#"["insert", {}, "zeta", 4]"#, source)).unwrap();
+    assert_eq!(keys(&replaced), ["zeta", "alpha", "mid"]);
+
+    assert_eq!(parsed.to_string(), r#"{"zeta":1,"alpha":2,"mid":3}"#);
+    assert_eq!(parsed, json::parse(r#"{"mid": 3, "alpha": 2, "zeta": 1}"#));
+}
+
+#[test]
+fn ordered_map_remove_keeps_order() {
+    let mut m: map::OrderedMap<i64> = [("a", 1), ("b", 2), ("c", 3)]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+    assert_eq!(m.remove("a"), Some(1));
+    assert_eq!(m.remove("a"), None);
+    m.insert("a".to_string(), 4);
+    assert_eq!(m.keys().collect::<Vec<_>>(), ["b", "c", "a"]);
+    assert_eq!(m.get("c"), Some(&3));
+}
+
+// serialization
+
+#[test]
+fn compact_output_is_valid_json() {
+    let o = json::parse(r#"{"name": "jl", "tags": [null, true, 1.5], "a\"b": {}}"#);
+    let text = o.to_string();
+    assert_eq!(text, r#"{"name":"jl","tags":[null,true,1.5],"a\"b":{}}"#);
+    assert_eq!(json::try_parse(&text).unwrap(), o);
+
+    let id = JObject::new_func(vec!["x"], JObject::String("x".to_string()));
+    assert_eq!(id.to_string(), r#"["f",["x"],"x"]"#);
+    assert_eq!(JObject::Null.to_string(), "null");
+}
+
+#[test]
+fn pretty_breaks_what_does_not_fit() {
+    use crate::serialize::pretty;
+
+    let o = json::parse(r#"{"name": "jl", "list": [1, 2, 3], "nested": {"deep": [[], {}]}}"#);
+    assert_eq!(
+        pretty(&o, 80),
+        r#"{"name": "jl", "list": [1, 2, 3], "nested": {"deep": [[], {}]}}"#
+    );
+    assert_eq!(
+        pretty(&o, 24),
+        r#"{
+  "name": "jl",
+  "list": [1, 2, 3],
+  "nested": {
+    "deep": [[], {}]
+  }
+}"#
+    );
+    assert_eq!(json::try_parse(&pretty(&o, 0)).unwrap(), o);
+}
+
+#[test]
+fn canonical_sorts_keys_and_normalizes_numbers() {
+    use crate::serialize::canonical;
+
+    let a = json::parse(r#"{"b": [3.0, -0.0, 1e21, 1e-7, 0.000001, 123.456], "a": 1}"#);
+    assert_eq!(
+        canonical(&a),
+        r#"{"a":1,"b":[3,0,1e+21,1e-7,0.000001,123.456]}"#
+    );
+    let b = json::parse(r#"{"a": 1.0, "b": [3, 0, 1e21, 1e-7, 1e-6, 123.456]}"#);
+    assert_eq!(canonical(&a), canonical(&b));
+
+    // Sorted by UTF-16 code units, so U+1F600 (a surrogate pair) sorts
+    // before U+FB01.
+    let o = json::parse(r#"{"ﬁ": 1, "😀": 2, "B": 3, "a": 4}"#);
+    assert_eq!(canonical(&o), r#"{"B":3,"a":4,"😀":2,"ﬁ":1}"#);
+
+    // Integers past 2^53 become the nearest double, as a reader would see.
+    let big = json::parse("[9007199254740993, 9007199254740992.0, -9007199254740993]");
+    assert_eq!(
+        canonical(&big),
+        "[9007199254740992,9007199254740992,-9007199254740992]"
+    );
+    assert_eq!(
+        canonical(&json::parse("9007199254740992")),
+        "9007199254740992"
+    );
+}
+
+#[test]
+fn non_finite_floats_are_written_as_null() {
+    use crate::serialize::{canonical, pretty};
+
+    let o = vec![f64::INFINITY, f64::NEG_INFINITY, f64::NAN, 1.5].to_jobject();
+    assert_eq!(o.to_string(), "[null,null,null,1.5]");
+    assert_eq!(canonical(&o), "[null,null,null,1.5]");
+    assert_eq!(pretty(&o, 80), "[null, null, null, 1.5]");
+    assert_eq!(pretty(&o, 0), "[\n  null,\n  null,\n  null,\n  1.5\n]");
+    assert!(json::try_parse(&o.to_string()).is_ok());
+}
+
+#[test]
+fn pretty_handles_deep_nesting() {
+    use crate::serialize::pretty;
+
+    let mut o = JObject::Number(1);
+    for _ in 0..json::MAX_NESTING {
+        o = JObject::List(vec![o]);
+    }
+    let text = pretty(&o, 80);
+    assert_eq!(json::try_parse(&text).unwrap(), o);
+}
+
+// null and the empty list
+
+#[test]
+fn null_and_empty_list_stay_distinct() {
+    let env = &mut env_with(&["std::logic"]);
+
+    assert_eq!(run(env, "[]").unwrap(), JObject::List(vec![]));
+    assert_eq!(run(env, "null").unwrap(), JObject::Null);
+    assert_ne!(JObject::Null, JObject::List(vec![]));
+
+    let source = r#"{"a":null,"b":[]}"#;
+    assert_eq!(run(env, source).unwrap().to_string(), source);
+    assert_eq!(
+        run(env, r#"["quote", [null, []]]"#).unwrap().to_string(),
+        "[null,[]]"
+    );
+}
+
+#[test]
+fn null_and_empty_list_are_both_false() {
+    let env = &mut env_with(&["std::logic"]);
+
+    for falsy in ["null", "[]", "false"] {
+        let source = format!(r#"["if", {}, "yes", "no"]"#, falsy);
+        assert_eq!(run(env, &source).unwrap(), "no".to_jobject());
+    }
+    for truthy in ["0", r#""""#, "{}", "[1]"] {
+        let source = format!(r#"["if", ["quote", {}], "yes", "no"]"#, truthy);
+        assert_eq!(run(env, &source).unwrap(), "yes".to_jobject());
+    }
+}
+
+// symbols
+
+fn symbols(text: &str) -> JObject {
+    json::try_parse_with(text, json::ParseOptions { symbols: true }).unwrap()
+}
+
+#[test]
+fn sigil_reads_symbols_only_when_enabled() {
+    assert_eq!(json::parse(r#""$x""#), "$x".to_jobject());
+    assert_eq!(symbols(r#""$x""#), JObject::Symbol("x".to_string()));
+    assert_eq!(symbols(r#""$$x""#), "$x".to_jobject());
+    assert_eq!(symbols(r#""$""#), "$".to_jobject());
+    assert_eq!(symbols(r#""x""#), "x".to_jobject());
+    assert_eq!(symbols(r#"{"$k": "$v"}"#).to_string(), r#"{"$k":"$v"}"#);
+    assert_eq!(symbols(r#""$x""#).typename(), "Symbol");
+}
+
+#[test]
+fn symbols_and_dollar_strings_print_apart() {
+    use crate::serialize::compact_with;
+
+    let options = json::ParseOptions { symbols: true };
+    let o = symbols(r#"["$x", "$$x", "x", "$", {"$k": "$$v"}]"#);
+    let text = compact_with(&o, options);
+    assert_eq!(text, r#"["$x","$$x","x","$$",{"$k":"$$v"}]"#);
+    assert_eq!(json::try_parse_with(&text, options).unwrap(), o);
+    // Without the sigil, strings are written as they are.
+    assert_eq!(json::parse(r#""$5""#).to_string(), r#""$5""#);
+}
+
+#[test]
+fn unbound_symbols_are_errors() {
+    let env = &mut env_with(&["std::math"]);
+    let kind = |res: Result<JObject, Error>| res.unwrap_err().kind().to_string();
+
+    assert_eq!(kind(eval(env, &symbols(r#""$y""#))), "unbound-symbol");
+    assert_eq!(
+        kind(eval(env, &symbols(r#"["$nope", 1]"#))),
+        "unbound-symbol"
+    );
+    // Plain strings keep falling back to themselves.
+    assert_eq!(eval(env, &symbols(r#""y""#)).unwrap(), "y".to_jobject());
+    assert_eq!(
+        eval(env, &symbols(r#"["nope", 1]"#)).unwrap(),
+        JObject::Null
+    );
+
+    eval(env, &symbols(r#"["def", "$x", 5]"#)).unwrap();
+    assert_eq!(eval(env, &symbols(r#""$x""#)).unwrap(), JObject::Number(5));
+    assert_eq!(
+        eval(env, &symbols(r#"["$+", "$x", 1]"#)).unwrap(),
+        JObject::Number(6)
+    );
+
+    // Once the reader marks variables, a plain string is never looked up.
+    env.parse_options.symbols = true;
+    assert_eq!(eval(env, &symbols(r#""x""#)).unwrap(), "x".to_jobject());
+    eval(
+        env,
+        &symbols(r#"["def", "$inc", ["f", ["$n"], ["+", "$n", 1]]]"#),
+    )
+    .unwrap();
+    assert_eq!(eval(env, &symbols(r#"["inc", 1]"#)).unwrap(), JObject::Null);
+    assert_eq!(
+        eval(env, &symbols(r#"["+", "$x", 1]"#)).unwrap(),
+        JObject::Number(6)
+    );
+}
+
+#[test]
+fn symbols_name_parameters_and_builtins() {
+    let env = &mut env_with(&["std::math", "std::array"]);
+
+    eval(
+        env,
+        &symbols(r#"["def", "$inc", ["f", ["$n"], ["$+", "$n", 1]]]"#),
+    )
+    .unwrap();
+    assert_eq!(
+        eval(env, &symbols(r#"["$map", "$inc", ["quote", [1, 2]]]"#)).unwrap(),
+        json::parse("[2, 3]")
+    );
+    // A symbol for a builtin evaluates to itself and can be called later.
+    assert_eq!(
+        eval(env, &symbols(r#""$+""#)).unwrap(),
+        JObject::Symbol("+".to_string())
+    );
+    assert_eq!(
+        eval(env, &symbols(r#"["$map", "$-", ["quote", [1, 2]]]"#)).unwrap(),
+        json::parse("[-1, -2]")
+    );
+}
+
+// strict mode
+
+#[test]
+fn strict_mode_rejects_unknown_names() {
+    let env = &mut Environment::builder().strict().build();
+    stdlib::import_builtin_library(env, "std::math").unwrap();
+    let error = |source: &str| run(&mut env.clone(), source).unwrap_err();
+
+    assert_eq!(
+        error(r#"["printn", 1]"#),
+        Error::new("unknown-function", "no function printn")
+    );
+    assert_eq!(
+        error(r#"["+", "x", 1]"#),
+        Error::new("unbound-symbol", "no variable x")
+    );
+    assert_eq!(
+        run(env, r#"["+", ["quote", 2], 1]"#).unwrap(),
+        JObject::Number(3)
+    );
+    // Builtin names are bound, so they can still be passed as values.
+    assert_eq!(run(env, r#""+""#).unwrap(), "+".to_jobject());
+
+    // With the `$` sigil, plain strings are literals again.
+    env.parse_options.symbols = true;
+    let program = json::try_parse_with(r#"["$def", "$s", "x"]"#, env.parse_options).unwrap();
+    assert_eq!(eval(env, &program).unwrap(), "x".to_jobject());
+
+    let lax = &mut env_with(&["std::math"]);
+    assert_eq!(run(lax, r#"["printn", 1]"#).unwrap(), JObject::Null);
+    assert_eq!(run(lax, r#""x""#).unwrap(), "x".to_jobject());
+}
+
+#[test]
+fn unknown_names_are_located_in_the_source() {
+    use crate::locate::locate;
+
+    fn located(env: &mut Environment, source: &str, options: json::ParseOptions) -> String {
+        let (program, spans) = json::try_parse_spanned(source, options).unwrap();
+        let err = eval(env, &program).unwrap_err();
+        locate(err, &program, &spans, source, options).to_string()
+    }
+    let plain = json::ParseOptions::default();
+    let env = &mut Environment::builder().strict().build();
+    stdlib::import_builtin_library(env, "std::logic").unwrap();
+
+    let source = "[\"program\",\n  [\"quote\", \"printn\"],\n  [\"printn\", 1]]";
+    assert_eq!(
+        located(env, source, plain),
+        "unknown-function: no function printn at line 3, column 4"
+    );
+    // Map keys aren't uses of the name.
+    let source = "[\"program\", {\"y\": 1},\n \"y\"]";
+    assert_eq!(
+        located(env, source, plain),
+        "unbound-symbol: no variable y at line 2, column 2"
+    );
+    // Nor are parameters, or names their function binds.
+    let source = r#"["program",
+  ["def", "n", 1],
+  ["def", "g", ["f", ["y"], "y"]],
+  ["def", "h", ["f", ["x"], "y"]],
+  ["h", 1]]"#;
+    assert_eq!(
+        located(env, source, plain),
+        "unbound-symbol: no variable y at line 4, column 29"
+    );
+    let source = r#"["program",
+  ["let", [["y", 1]], ["case", 1, [["y"], "y"]]],
+  ["try", ["throw", 1], ["catch", "y", "y"]],
+  ["quasiquote", ["y", [["unquote", "y"]], ["splice-unquote", "y"]]]]"#;
+    assert_eq!(
+        located(env, source, plain),
+        "unbound-symbol: no variable y at line 4, column 63"
+    );
+    // A name the program only passes around as data has nowhere to point.
+    let source = r#"["program",
+  ["def", "call", ["macro", ["x"], ["x", 1]]],
+  ["call", "printn"]]"#;
+    assert_eq!(
+        located(env, source, plain),
+        "unknown-function: no function printn"
+    );
+
+    let options = json::ParseOptions { symbols: true };
+    let source = "[\"program\", \"x\",\n \"$x\"]";
+    assert_eq!(
+        located(env, source, options),
+        "unbound-symbol: no variable x at line 2, column 2"
+    );
+
+    let other = Error::new("bad-arity", "1 != 2");
+    let (program, spans) = json::try_parse_spanned(source, options).unwrap();
+    assert_eq!(
+        locate(other.clone(), &program, &spans, source, options),
+        other
+    );
+    assert_eq!(json::line_column("ab\ncé\"d", 6), (2, 3));
+}
+
+#[test]
+fn parsed_values_know_where_they_start() {
+    let (o, span) =
+        json::try_parse_spanned(r#" ["é", {"k": [1]}, 2]"#, json::ParseOptions::default()).unwrap();
+    assert_eq!(o, json::parse(r#"["é", {"k": [1]}, 2]"#));
+    let offsets = |span: &json::Span| span.children.iter().map(|s| s.offset).collect::<Vec<_>>();
+    assert_eq!(span.offset, 1);
+    assert_eq!(offsets(&span), [2, 8, 20]);
+    assert_eq!(offsets(&span.children[1]), [14]);
+    assert_eq!(offsets(&span.children[1].children[0]), [15]);
+}
+
+// local bindings
+
+#[test]
+fn let_binds_sequentially_and_locally() {
+    let env = &mut env_with(&["std::math"]);
+
+    let source = r#"["let", [["x", 1], ["y", ["+", "x", 1]]], ["*", "x", "y"]]"#;
+    assert_eq!(run(env, source).unwrap(), JObject::Number(2));
+    assert_eq!(env.lookup("x"), None);
+    assert!(env.scope.is_none());
+
+    // Later bindings shadow earlier ones, and the body is an implicit `do`.
+    let source = r#"["let", [["x", 1], ["x", ["+", "x", 10]]], ["def", "g", "x"], ["+", "x", 1]]"#;
+    assert_eq!(run(env, source).unwrap(), JObject::Number(12));
+    assert_eq!(env.lookup("g"), Some(JObject::Number(11)));
+
+    // A function made in a `let` keeps its bindings.
+    run(
+        env,
+        r#"["def", "add5", ["let", [["n", 5]], ["f", ["x"], ["+", "x", "n"]]]]"#,
+    )
+    .unwrap();
+    assert_eq!(run(env, r#"["add5", 1]"#).unwrap(), JObject::Number(6));
+
+    assert_eq!(run(env, r#"["do", 1, 2, 3]"#).unwrap(), JObject::Number(3));
+    assert_eq!(run(env, r#"["do"]"#).unwrap(), JObject::Null);
+    let kind = |source: &str| {
+        run(&mut env_with(&["std::math"]), source)
+            .unwrap_err()
+            .kind()
+            .to_string()
+    };
+    assert_eq!(kind(r#"["let", [["x"]], "x"]"#), "bad-let");
+    assert_eq!(kind(r#"["let", "x"]"#), "bad-let");
+    assert_eq!(kind(r#"["let"]"#), "bad-let");
+}
+
+#[test]
+fn letrec_allows_mutual_recursion() {
+    let env = &mut env_with(&["std::math", "std::logic"]);
+
+    let source = r#"
+        ["letrec", [
+            ["even?", ["f", ["n"], ["if", ["=", "n", 0], true, ["odd?", ["-", "n", 1]]]]],
+            ["odd?", ["f", ["n"], ["if", ["=", "n", 0], false, ["even?", ["-", "n", 1]]]]]],
+          ["even?", 30000]]"#;
+    assert_eq!(run(env, source).unwrap(), JObject::Bool(true));
+    assert_eq!(env.lookup("even?"), None);
+
+    // Plain `let` doesn't see its own binding.
+    let source = r#"["let", [["g", ["f", [], "g"]]], ["g"]]"#;
+    assert_eq!(run(env, source).unwrap(), "g".to_jobject());
+}
+
+#[test]
+fn letrec_frames_are_freed() {
+    let env = &mut env_with(&["std::math", "std::logic"]);
+
+    let source = r#"
+        ["letrec", [
+            ["down", ["f", ["n"], ["if", ["=", "n", 0], "done", ["down", ["-", "n", 1]]]]]],
+          "down"]"#;
+    let down = run(env, source).unwrap();
+    let JObject::Func { closure, .. } = &down else {
+        panic!("not a function: {}", down);
+    };
+    let frame = std::rc::Rc::downgrade(closure.0.as_ref().unwrap());
+
+    // The function outlives the `letrec` and can still call itself.
+    env.symbols.insert("down".to_string(), down);
+    assert_eq!(run(env, r#"["down", 3]"#).unwrap(), "done".to_jobject());
+
+    env.symbols.remove("down");
+    assert!(frame.upgrade().is_none());
+}
+
+// std::logic
+
+#[test]
+fn boolean_operators() {
+    let env = &mut env_with(&["std::logic"]);
+    let check = |env: &mut Environment, source: &str, expected: bool| {
+        assert_eq!(
+            run(env, source).unwrap(),
+            JObject::Bool(expected),
+            "{}",
+            source
+        );
+    };
+
+    check(env, r#"["and"]"#, true);
+    check(env, r#"["and", 1, "x", true]"#, true);
+    check(env, r#"["and", 1, null, ["crash"]]"#, false);
+    check(env, r#"["not", []]"#, true);
+    check(env, r#"["not", 0]"#, false);
+    check(env, r#"["xor", true, null]"#, true);
+    check(env, r#"["xor", 1, 2]"#, false);
+    assert_eq!(run(env, r#"["not"]"#).unwrap_err().kind(), "bad-arity");
+}
+
+#[test]
+fn deep_equality() {
+    let env = &mut env_with(&["std::logic"]);
+
+    assert_eq!(
+        run(
+            env,
+            r#"["=", ["quote", {"a": [1, {"b": 2.0}]}], ["quote", {"a": [1, {"b": 2.0}]}]]"#
+        )
+        .unwrap(),
+        JObject::Bool(true)
+    );
+    assert_eq!(
+        run(env, r#"["=", ["quote", [1, 2]], ["quote", [1, 2, 3]]]"#).unwrap(),
+        JObject::Bool(false)
+    );
+    assert_eq!(
+        run(env, r#"["=", null, []]"#).unwrap(),
+        JObject::Bool(false)
+    );
+    assert_eq!(
+        run(env, r#"["=", "a", "a", "a"]"#).unwrap(),
+        JObject::Bool(true)
+    );
+
+    // `=` is `==`, so a Number never equals a Float, at any depth.
+    assert_eq!(run(env, r#"["=", 1, 1.0]"#).unwrap(), JObject::Bool(false));
+    assert_eq!(
+        run(env, r#"["=", ["quote", [1]], ["quote", [1.0]]]"#).unwrap(),
+        JObject::Bool(false)
+    );
+    assert_eq!(
+        run(env, r#"["=", -0.0, 0.0]"#).unwrap(),
+        JObject::Bool(true)
+    );
+
+    // Functions are equal only if they share what they captured.
+    run(
+        env,
+        r#"["def", "adder", ["f", ["n"], ["f", ["x"], ["+", "x", "n"]]]]"#,
+    )
+    .unwrap();
+    run(env, r#"["def", "add1", ["adder", 1]]"#).unwrap();
+    assert_eq!(
+        run(env, r#"["=", "add1", "add1"]"#).unwrap(),
+        JObject::Bool(true)
+    );
+    assert_eq!(
+        run(env, r#"["=", "add1", ["adder", 2]]"#).unwrap(),
+        JObject::Bool(false)
+    );
+
+    // std::math's `=` compares numbers by value instead, at every depth.
+    // Whichever module is imported last decides what `=` means.
+    stdlib::import_builtin_library(env, "std::math").unwrap();
+    assert_eq!(run(env, r#"["=", 1, 1.0]"#).unwrap(), JObject::Bool(true));
+    assert_eq!(
+        run(
+            env,
+            r#"["=", ["quote", {"a": [1, {"b": 2.0}]}], ["quote", {"a": [1.0, {"b": 2}]}]]"#
+        )
+        .unwrap(),
+        JObject::Bool(true)
+    );
+    assert_eq!(
+        run(env, r#"["!=", ["quote", [1]], ["quote", [1.0]]]"#).unwrap(),
+        JObject::Bool(false)
+    );
+    assert_eq!(
+        run(env, r#"["=", ["quote", [1]], ["quote", [1, 2]]]"#).unwrap(),
+        JObject::Bool(false)
+    );
+    stdlib::import_builtin_library(env, "std::logic").unwrap();
+    assert_eq!(run(env, r#"["=", 1, 1.0]"#).unwrap(), JObject::Bool(false));
+    assert_eq!(
+        run(env, r#"["<", -0.0, 0.0]"#).unwrap(),
+        JObject::Bool(false)
+    );
+    assert_eq!(
+        run(env, r#"["<=", -0.0, 0.0]"#).unwrap(),
+        JObject::Bool(true)
+    );
+}
+
+#[test]
+fn conditional_forms() {
+    let env = &mut env_with(&["std::math", "std::logic"]);
+    let classify = r#"["def", "classify", ["f", ["n"],
+        ["cond", [["<", "n", 0], "negative"],
+                 [["=", "n", 0], "zero"],
+                 ["else", ["def", "seen", "n"], "positive"]]]]"#;
+    run(env, classify).unwrap();
+
+    assert_eq!(
+        run(env, r#"["classify", -3]"#).unwrap(),
+        "negative".to_jobject()
+    );
+    assert_eq!(run(env, r#"["classify", 0]"#).unwrap(), "zero".to_jobject());
+    assert_eq!(
+        run(env, r#"["classify", 7]"#).unwrap(),
+        "positive".to_jobject()
+    );
+    assert_eq!(env.lookup("seen"), Some(JObject::Number(7)));
+    assert_eq!(
+        run(env, r#"["cond", [null, 1], [5]]"#).unwrap(),
+        JObject::Number(5)
+    );
+    assert_eq!(run(env, r#"["cond", [false, 1]]"#).unwrap(), JObject::Null);
+    assert_eq!(run(env, r#"["cond", 1]"#).unwrap_err().kind(), "bad-cond");
+
+    assert_eq!(
+        run(env, r#"["when", true, 1, 2]"#).unwrap(),
+        JObject::Number(2)
+    );
+    assert_eq!(
+        run(env, r#"["when", false, ["crash"]]"#).unwrap(),
+        JObject::Null
+    );
+    assert_eq!(
+        run(env, r#"["unless", false, 1, 2]"#).unwrap(),
+        JObject::Number(2)
+    );
+    assert_eq!(
+        run(env, r#"["unless", true, ["crash"]]"#).unwrap(),
+        JObject::Null
+    );
+
+    let describe = r#"["def", "describe", ["f", ["x"],
+        ["case", "x",
+            [[1, 2, 3], "small"],
+            ["many", "word"],
+            [[[1, 2]], "pair"],
+            ["else", "other"]]]]"#;
+    run(env, describe).unwrap();
+    assert_eq!(
+        run(env, r#"["describe", 2]"#).unwrap(),
+        "small".to_jobject()
+    );
+    // values match by `==`, like std::logic's `=`
+    assert_eq!(
+        run(env, r#"["describe", 2.0]"#).unwrap(),
+        "other".to_jobject()
+    );
+    assert_eq!(
+        run(env, r#"["describe", ["quote", "many"]]"#).unwrap(),
+        "word".to_jobject()
+    );
+    assert_eq!(
+        run(env, r#"["describe", ["quote", [1, 2]]]"#).unwrap(),
+        "pair".to_jobject()
+    );
+    assert_eq!(
+        run(env, r#"["describe", 9]"#).unwrap(),
+        "other".to_jobject()
+    );
+    assert_eq!(
+        run(env, r#"["case", 1, [2, "no"]]"#).unwrap(),
+        JObject::Null
+    );
+    assert_eq!(
+        run(env, r#"["case", 1, 2]"#).unwrap_err().kind(),
+        "bad-case"
+    );
+}
+
+#[test]
+fn cond_branches_are_tail_calls() {
+    let env = &mut env_with(&["std::math", "std::logic"]);
+    run(
+        env,
+        r#"["def", "count", ["f", ["n"],
+            ["cond", [["=", "n", 0], "done"],
+                     ["else", ["count", ["-", "n", 1]]]]]]"#,
+    )
+    .unwrap();
+    env.limits.max_depth = Some(100);
+    assert_eq!(
+        run(env, r#"["count", 30000]"#).unwrap(),
+        "done".to_jobject()
+    );
+}
+
+// std::array
+
+#[test]
+fn array_transformations() {
+    let env = &mut env_with(&["std::math", "std::array", "std::logic"]);
+    run(env, r#"["def", "xs", ["quote", [3, 1, 4, 1, 5]]]"#).unwrap();
+    run(
+        env,
+        r#"["def", "odd?", ["f", ["n"], ["=", ["mod", "n", 2], 1]]]"#,
+    )
+    .unwrap();
+
+    check(env, r#"["filter", "odd?", "xs"]"#, "[3, 1, 1, 5]");
+    check(env, r#"["reduce", "+", "xs"]"#, "14");
+    check(env, r#"["reduce", "+", []]"#, "null");
+    check(
+        env,
+        r#"["fold", ["f", ["acc", "x"], ["append", "acc", "x"]], [], "xs"]"#,
+        "[3, 1, 4, 1, 5]",
+    );
+    check(env, r#"["range", 3]"#, "[0, 1, 2]");
+    check(env, r#"["range", 2, 5]"#, "[2, 3, 4]");
+    check(env, r#"["range", 5, 0, -2]"#, "[5, 3, 1]");
+    check(
+        env,
+        r#"["concat", "xs", [], ["quote", [9]]]"#,
+        "[3, 1, 4, 1, 5, 9]",
+    );
+    check(env, r#"["nth", "xs", 2]"#, "4");
+    check(env, r#"["nth", "xs", 10]"#, "null");
+    check(env, r#"["slice", "xs", 1, 3]"#, "[1, 4]");
+    check(env, r#"["slice", "xs", -5, 99]"#, "[3, 1, 4, 1, 5]");
+    check(env, r#"["slice", "xs", 4, 2]"#, "[]");
+    check(env, r#"["reverse", "xs"]"#, "[5, 1, 4, 1, 3]");
+    check(
+        env,
+        r#"["zip", "xs", ["quote", ["a", "b"]]]"#,
+        r#"[[3, "a"], [1, "b"]]"#,
+    );
+    check(
+        env,
+        r#"["enumerate", ["quote", ["a", "b"]]]"#,
+        r#"[[0, "a"], [1, "b"]]"#,
+    );
+    check(env, r#"["find", ["f", ["n"], [">", "n", 3]], "xs"]"#, "4");
+    check(
+        env,
+        r#"["find", ["f", ["n"], [">", "n", 9]], "xs"]"#,
+        "null",
+    );
+    check(env, r#"["any", "odd?", "xs"]"#, "true");
+    check(env, r#"["all", "odd?", "xs"]"#, "false");
+    check(
+        env,
+        r#"["flatten", ["quote", [[1, [2]], 3, []]]]"#,
+        "[1, [2], 3]",
+    );
+    check(
+        env,
+        r#"["flat-map", ["f", ["n"], ["range", "n"]], ["quote", [1, 2]]]"#,
+        "[0, 0, 1]",
+    );
+    check(env, r#"["take", 2, "xs"]"#, "[3, 1]");
+    check(env, r#"["drop", 2, "xs"]"#, "[4, 1, 5]");
+    check(env, r#"["take", 9, "xs"]"#, "[3, 1, 4, 1, 5]");
+    check(
+        env,
+        r#"["group-by", "odd?", "xs"]"#,
+        r#"{"true": [3, 1, 1, 5], "false": [4]}"#,
+    );
+    check(env, r#"["partition", "odd?", "xs"]"#, "[[3, 1, 1, 5], [4]]");
+    check(
+        env,
+        r#"["distinct", ["quote", [1, 1.0, "1", [1], [1], [1.0]]]]"#,
+        r#"[1, 1.0, "1", [1], [1.0]]"#,
+    );
+}
+
+#[test]
+fn array_errors() {
+    let env = &mut env_with(&["std::math", "std::array", "std::logic"]);
+
+    assert_eq!(error_kind(env, r#"["len", "x"]"#), "bad-type");
+    assert_eq!(error_kind(env, r#"["range", 1, 2, 0]"#), "bad-range");
+    assert_eq!(error_kind(env, r#"["range"]"#), "bad-arity");
+    assert_eq!(
+        error_kind(env, r#"["len", ["range", 1000000000000]]"#),
+        "limit-exceeded"
+    );
+    assert_eq!(error_kind(env, r#"["concat", [], 1]"#), "bad-type");
+    assert_eq!(
+        error_kind(env, r#"["sort", ["quote", [1, "a"]]]"#),
+        "bad-type"
+    );
+}
+
+#[test]
+fn sorting() {
+    let env = &mut env_with(&["std::math", "std::array", "std::logic"]);
+    check(
+        env,
+        r#"["sort", ["quote", [3, 1.5, -2, 10]]]"#,
+        "[-2, 1.5, 3, 10]",
+    );
+    check(
+        env,
+        r#"["sort", ["quote", ["pear", "apple", "Fig"]]]"#,
+        r#"["Fig", "apple", "pear"]"#,
+    );
+    check(
+        env,
+        r#"["sort-by", ">", ["quote", [3, 1, 2]]]"#,
+        "[3, 2, 1]",
+    );
+    // A three-way comparator, and stability: equal lengths keep their order.
+    check(
+        env,
+        r#"["sort-by", ["f", ["a", "b"], ["-", ["len", "a"], ["len", "b"]]],
+            ["quote", [[1, 2], [3], [4], []]]]"#,
+        "[[], [3], [4], [1, 2]]",
+    );
+    // A comparator that isn't an order still terminates.
+    check(
+        env,
+        r#"["len", ["sort-by", ["f", ["a", "b"], true], ["range", 50]]]"#,
+        "50",
+    );
+    assert_eq!(
+        run(
+            env,
+            r#"["sort-by", ["f", ["a", "b"], ["throw", "no"]], ["range", 2]]"#
+        )
+        .unwrap_err(),
+        Error::Thrown("no".to_jobject())
+    );
+}
+
+// std::object
+
+const DOC: &str = r#"["def", "doc", ["quote", {"name": "jl", "tags": ["a", "b"], "meta": {"stars": 1, "by": {"x": 1}}}]]"#;
+
+#[test]
+fn object_accessors() {
+    let env = &mut env_with(&["std::math", "std::object", "std::logic"]);
+    run(env, DOC).unwrap();
+    let doc = run(env, r#""doc""#).unwrap().to_string();
+    check(env, r#"["get", "doc", "name"]"#, r#""jl""#);
+    check(env, r#"["get", "doc", "nope"]"#, "null");
+    check(env, r#"["get", "doc", "nope", 0]"#, "0");
+    check(
+        env,
+        r#"["remove", "doc", "tags"]"#,
+        r#"{"name": "jl", "meta": {"stars": 1, "by": {"x": 1}}}"#,
+    );
+    check(env, r#"["keys", "doc"]"#, r#"["name", "tags", "meta"]"#);
+    check(env, r#"["values", {"a": 1, "b": 2}]"#, "[1, 2]");
+    check(
+        env,
+        r#"["entries", {"a": 1, "b": 2}]"#,
+        r#"[["a", 1], ["b", 2]]"#,
+    );
+    check(env, r#"["from-entries", ["entries", "doc"]]"#, &doc);
+    check(
+        env,
+        r#"["from-entries", ["quote", [["b", 1], [2, 3]]]]"#,
+        r#"{"b": 1, "2": 3}"#,
+    );
+    check(
+        env,
+        r#"["select-keys", "doc", ["quote", ["meta", "name", "zzz"]]]"#,
+        r#"{"name": "jl", "meta": {"stars": 1, "by": {"x": 1}}}"#,
+    );
+    check(
+        env,
+        r#"["map-values", ["f", ["v"], ["*", "v", 10]], {"a": 1, "b": 2}]"#,
+        r#"{"a": 10, "b": 20}"#,
+    );
+    check(
+        env,
+        r#"["filter-entries", ["f", ["k", "v"], ["=", "k", "b"]], {"a": 1, "b": 2}]"#,
+        r#"{"b": 2}"#,
+    );
+    check(
+        env,
+        r#"["update", {"n": 1}, "n", ["f", ["v"], ["+", "v", 1]]]"#,
+        r#"{"n": 2}"#,
+    );
+    check(
+        env,
+        r#"["update", {"n": 1}, "m", ["f", ["v"], ["=", "v", null]]]"#,
+        r#"{"n": 1, "m": true}"#,
+    );
+}
+
+#[test]
+fn merging_objects() {
+    let env = &mut env_with(&["std::math", "std::object", "std::logic"]);
+    run(env, DOC).unwrap();
+    check(
+        env,
+        r#"["merge", {"a": 1, "b": {"x": 1}}, {"b": {"y": 2}, "c": 3}]"#,
+        r#"{"a": 1, "b": {"y": 2}, "c": 3}"#,
+    );
+    check(
+        env,
+        r#"["deep-merge", {"a": 1, "b": {"x": 1}}, {"b": {"y": 2}, "a": {"z": 0}}]"#,
+        r#"{"a": {"z": 0}, "b": {"x": 1, "y": 2}}"#,
+    );
+    check(env, r#"["merge"]"#, "{}");
+    assert_eq!(
+        run(env, r#"["merge", {}, 1]"#).unwrap_err().kind(),
+        "bad-type"
+    );
+}
+
+#[test]
+fn object_paths() {
+    let env = &mut env_with(&["std::math", "std::object", "std::logic"]);
+    run(env, DOC).unwrap();
+    let doc = run(env, r#""doc""#).unwrap().to_string();
+    check(
+        env,
+        r#"["get-in", "doc", ["quote", ["meta", "by", "x"]]]"#,
+        "1",
+    );
+    check(
+        env,
+        r#"["get-in", "doc", ["quote", ["tags", 1]]]"#,
+        r#""b""#,
+    );
+    check(
+        env,
+        r#"["get-in", "doc", ["quote", ["tags", 5]], "none"]"#,
+        r#""none""#,
+    );
+    check(env, r#"["get-in", "doc", []]"#, &doc);
+    check(
+        env,
+        r#"["assoc-in", "doc", ["quote", ["meta", "new", "deep"]], true]"#,
+        r#"{"name": "jl", "tags": ["a", "b"], "meta": {"stars": 1, "by": {"x": 1}, "new": {"deep": true}}}"#,
+    );
+    check(
+        env,
+        r#"["update-in", "doc", ["quote", ["meta", "stars"]], ["f", ["n"], ["+", "n", 1]]]"#,
+        r#"{"name": "jl", "tags": ["a", "b"], "meta": {"stars": 2, "by": {"x": 1}}}"#,
+    );
+    check(
+        env,
+        r#"["assoc-in", "doc", ["quote", ["tags", 0]], "z"]"#,
+        r#"{"name": "jl", "tags": ["z", "b"], "meta": {"stars": 1, "by": {"x": 1}}}"#,
+    );
+    check(
+        env,
+        r#"["assoc-in", null, ["quote", ["a", "b"]], 1]"#,
+        r#"{"a": {"b": 1}}"#,
+    );
+
+    assert_eq!(
+        error_kind(env, r#"["assoc-in", "doc", ["quote", ["tags", 9]], 1]"#),
+        "bad-path"
+    );
+    assert_eq!(
+        error_kind(env, r#"["assoc-in", "doc", ["quote", ["name", "x"]], 1]"#),
+        "bad-path"
+    );
+    assert_eq!(
+        error_kind(env, r#"["get-in", "doc", ["quote", [true]]]"#),
+        "bad-path"
+    );
+    assert_eq!(error_kind(env, r#"["insert", {}, true, 1]"#), "bad-type");
+}
+
+// std::string
+
+#[test]
+fn string_functions() {
+    let env = &mut env_with(&["std::string"]);
+    check(env, r#"["concat", "ab", "", "cé"]"#, r#""abcé""#);
+    check(env, r#"["length", "héllo😀"]"#, "6");
+    check(env, r#"["substring", "héllo", 1, 3]"#, r#""él""#);
+    check(env, r#"["substring", "héllo", 3]"#, r#""lo""#);
+    check(env, r#"["substring", "héllo", -2, 99]"#, r#""héllo""#);
+    check(env, r#"["split", "a,b,,c", ","]"#, r#"["a", "b", "", "c"]"#);
+    check(env, r#"["split", "añb", ""]"#, r#"["a", "ñ", "b"]"#);
+    check(
+        env,
+        r#"["join", ["quote", ["a", 1, null]], "-"]"#,
+        r#""a-1-null""#,
+    );
+    check(env, r#"["trim", "  hi \n"]"#, r#""hi""#);
+    check(env, r#"["upper", "straße"]"#, r#""STRASSE""#);
+    check(env, r#"["lower", "ÉCOLE"]"#, r#""école""#);
+    check(env, r#"["starts-with", "jl-lang", "jl"]"#, "true");
+    check(env, r#"["ends-with", "jl-lang", "jl"]"#, "false");
+    check(env, r#"["contains", "jl-lang", "-l"]"#, "true");
+    check(env, r#"["replace", "a-b-c", "-", "+"]"#, r#""a+b+c""#);
+    check(env, r#"["index-of", "héllo", "l"]"#, "2");
+    check(env, r#"["index-of", "héllo", "z"]"#, "null");
+    check(env, r#"["pad-left", "7", 3, "0"]"#, r#""007""#);
+    check(env, r#"["pad-right", "ab", 4]"#, r#""ab  ""#);
+    check(env, r#"["pad-left", "long", 2]"#, r#""long""#);
+    check(env, r#"["chars", "hé"]"#, r#"["h", "é"]"#);
+    check(env, r#"["repeat", "ab", 3]"#, r#""ababab""#);
+    check(env, r#"["repeat", "ab", 0]"#, r#""""#);
+}
+
+#[test]
+fn string_format() {
+    let env = &mut env_with(&["std::string"]);
+    let check = |env: &mut Environment, source: &str, expected: &str| {
+        assert_eq!(
+            run(env, source).unwrap(),
+            expected.to_jobject(),
+            "{}",
+            source
+        );
+    };
+
+    check(
+        env,
+        r#"["format", "{} scored {} ({})", "ana", 9.5, ["quote", [1, "x"]]]"#,
+        r#"ana scored 9.5 ([1,"x"])"#,
+    );
+    check(env, r#"["format", "{{}} {}", true]"#, "{} true");
+    check(env, r#"["format", "plain"]"#, "plain");
+
+    assert_eq!(error_kind(env, r#"["format", "{} {}", 1]"#), "bad-format");
+    assert_eq!(error_kind(env, r#"["format", "{}", 1, 2]"#), "bad-format");
+    assert_eq!(error_kind(env, r#"["format", "{x}"]"#), "bad-format");
+    assert_eq!(
+        error_kind(env, r#"["pad-left", "a", 3, "ab"]"#),
+        "bad-argument"
+    );
+    assert_eq!(error_kind(env, r#"["repeat", "a", -1]"#), "bad-argument");
+    assert_eq!(
+        error_kind(env, r#"["repeat", "x", 100000000000]"#),
+        "limit-exceeded"
+    );
+    assert_eq!(
+        error_kind(env, r#"["pad-left", "x", 9223372036854775807]"#),
+        "limit-exceeded"
+    );
+    assert_eq!(error_kind(env, r#"["length", 1]"#), "bad-type");
+    assert_eq!(
+        error_kind(env, r#"["concat", "a", ["quote", ["b"]]]"#),
+        "bad-type"
+    );
+}
+
+#[test]
+fn concat_works_on_lists_and_strings() {
+    let env = &mut env_with(&["std::string", "std::array"]);
+    assert_eq!(
+        run(env, r#"["concat", "a", "b"]"#).unwrap(),
+        "ab".to_jobject()
+    );
+    assert_eq!(
+        run(env, r#"["concat", ["quote", [1]], ["quote", [2]]]"#).unwrap(),
+        json::parse("[1, 2]")
+    );
+    assert!(Environment::builder()
+        .pure()
+        .build()
+        .capabilities
+        .allows_module("std::string"));
+}
+
+// std::regex
+
+#[test]
+fn regex_engine() {
+    use crate::regex::Regex;
+
+    let full = |pattern: &str, text: &str| {
+        let text: Vec<char> = text.chars().collect();
+        Regex::new(pattern)
+            .unwrap()
+            .is_full_match(&text, &mut |_| Ok(()))
+            .unwrap()
+    };
+    assert!(full(r"\d{3}-\d{4}", "555-1234"));
+    assert!(!full(r"\d{3}-\d{4}", "555-12345"));
+    assert!(full(r"(ab|cd)+e?", "abcdab"));
+    assert!(full(r"[a-c\d_]*", "ab1_c"));
+    assert!(!full(r"[^a-c]", "b"));
+    assert!(full(r"a.c", "a€c"));
+    assert!(!full(r"a.c", "a\nc"));
+    assert!(full(r"x{2,}y{,3}", "xxxy{,3}"));
+    assert!(full(r"(a*)*b", "aaab"));
+    assert!(!full(r"(a*)*b", "aaaa"));
+    assert!(full(r"\bword\b.*", "word up"));
+    assert!(full(r"[-a]\.[b-]", "-.-"));
+
+    let first = |pattern: &str, text: &str| {
+        let chars: Vec<char> = text.chars().collect();
+        let caps = Regex::new(pattern)
+            .unwrap()
+            .find_at(&chars, 0, &mut |_| Ok(()))
+            .unwrap()?;
+        let (s, e) = caps[0].unwrap();
+        Some(chars[s..e].iter().collect::<String>())
+    };
+    assert_eq!(first(r"a+?", "caaa").as_deref(), Some("a"));
+    assert_eq!(first(r"a+", "caaa").as_deref(), Some("aaa"));
+    assert_eq!(first(r"<.*?>", "<a><b>").as_deref(), Some("<a>"));
+    assert_eq!(first(r"^b", "ab"), None);
+    assert_eq!(first(r"b$", "ab").as_deref(), Some("b"));
+
+    for bad in [
+        "(a",
+        "a)",
+        "*a",
+        "[a",
+        r"\q",
+        "a{3,1}",
+        "(?<n>a)(?<n>b)",
+        "(?x)",
+        "^*",
+        "a{100000}",
+    ] {
+        assert_eq!(Regex::new(bad).unwrap_err().kind(), "bad-regex", "{}", bad);
+    }
+    let deep = "(".repeat(100_000) + &")".repeat(100_000);
+    assert_eq!(Regex::new(&deep).unwrap_err().kind(), "bad-regex");
+    let stacked = "a".to_string() + &"?".repeat(100_000);
+    assert_eq!(Regex::new(&stacked).unwrap_err().kind(), "bad-regex");
+}
+
+#[test]
+fn regex_matching_is_bounded() {
+    let env = &mut Environment::builder().build();
+    run(env, r#"["import", "std::regex"]"#).unwrap();
+    // Long text doesn't grow the stack, and nested stars don't backtrack.
+    let long = format!(r#"["matches?", "a*", "{}"]"#, "a".repeat(200_000));
+    assert_eq!(run(env, &long), Ok(JObject::Bool(true)));
+    let almost = format!(r#"["matches?", "(a*)*b", "{}"]"#, "a".repeat(5_000));
+    assert_eq!(run(env, &almost), Ok(JObject::Bool(false)));
+
+    // The work counts against the step limit.
+    env.limits.max_steps = Some(env.steps + 10_000);
+    assert_eq!(run(env, &long).unwrap_err().kind(), "limit-exceeded");
+}
+
+#[test]
+fn regex_builtins() {
+    let env = &mut env_with(&["std::regex"]);
+    check(env, r#"["matches?", "\\d+", "123"]"#, "true");
+    check(env, r#"["matches?", "\\d+", "123a"]"#, "false");
+    check(env, r#"["re-find", "\\d+", "ab 12 34"]"#, r#""12""#);
+    check(env, r#"["re-find", "\\d+", "none"]"#, "null");
+    check(
+        env,
+        r#"["find-all", "\\d+", "ab 12 34"]"#,
+        r#"["12", "34"]"#,
+    );
+    check(
+        env,
+        r#"["captures", "(?<level>[A-Z]+) (\\w+)(!)?", "at WARN disk"]"#,
+        r#"{"0": "WARN disk", "1": "WARN", "2": "disk", "3": null, "level": "WARN"}"#,
+    );
+    check(env, r#"["captures", "x", "y"]"#, "null");
+    check(
+        env,
+        r#"["replace-all", "(?<k>\\w+)=(\\w+)", "a=1 b=2", "${2}:$k$$"]"#,
+        r#""1:a$ 2:b$""#,
+    );
+    check(
+        env,
+        r#"["replace-all", "\\d", "a1b2", ["f", ["m"], ["quote", "*"]]]"#,
+        r#""a*b*""#,
+    );
+    check(
+        env,
+        r#"["split-by", "\\s*,\\s*", "a , b,c"]"#,
+        r#"["a", "b", "c"]"#,
+    );
+    check(env, r#"["split-by", "", "abc"]"#, r#"["a", "b", "c"]"#);
+
+    assert_eq!(error_kind(env, r#"["re-find", "(", "x"]"#), "bad-regex");
+    assert_eq!(
+        error_kind(env, r#"["replace-all", "a", "a", "$9"]"#),
+        "bad-replacement"
+    );
+    assert_eq!(
+        error_kind(env, r#"["replace-all", "a", "a", "$"]"#),
+        "bad-replacement"
+    );
+}
+
+#[test]
+fn array_and_regex_finds_work_together() {
+    let env = &mut env_with(&["std::array", "std::regex"]);
+    check(
+        env,
+        r#"["find", ["f", ["x"], ["quote", true]], ["quote", [1, 2, 3]]]"#,
+        "1",
+    );
+    check(env, r#"["re-find", "b+", "abbc"]"#, r#""bb""#);
+    assert_eq!(error_kind(env, r#"["find", "b+", "abbc"]"#), "bad-type");
+}
+
+// std::io
+
+#[test]
+fn file_io() {
+    let dir = std::env::temp_dir().join(format!("jl-file-io-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("out.txt").to_string_lossy().replace('\\', "/");
+    let dir_path = dir.to_string_lossy().replace('\\', "/");
+
+    let env = &mut Environment::builder().build();
+    stdlib::import_builtin_library(env, "std::io").unwrap();
+    let call = |env: &mut Environment, fname: &str, args: &str| {
+        run(env, &format!(r#"["{}", {}]"#, fname, args))
+    };
+    let file = format!("{:?}", path);
+
+    assert_eq!(call(env, "file-exists?", &file), Ok(JObject::Bool(false)));
+    call(env, "write-file", &format!(r#"{}, "one\n""#, file)).unwrap();
+    call(env, "append-file", &format!(r#"{}, "two\n""#, file)).unwrap();
+    assert_eq!(call(env, "read-file", &file), Ok("one\ntwo\n".to_jobject()));
+    assert_eq!(call(env, "file-exists?", &file), Ok(JObject::Bool(true)));
+    assert_eq!(
+        call(env, "list-dir", &format!("{:?}", dir_path)).unwrap(),
+        json::parse(r#"["out.txt"]"#)
+    );
+    call(env, "delete-file", &file).unwrap();
+    assert_eq!(call(env, "file-exists?", &file), Ok(JObject::Bool(false)));
+
+    let err = call(env, "read-file", &file).unwrap_err();
+    assert_eq!(err.kind(), "io-error");
+    assert_eq!(
+        call(env, "delete-file", &file).unwrap_err().kind(),
+        "io-error"
+    );
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+#[test]
+fn print_writes_plain_text() {
+    use crate::stdlib::io::write_plain;
+
+    let values = [
+        "3 items\n".to_jobject(),
+        json::parse(r#"["a", 1]"#),
+        JObject::Null,
+    ];
+    let mut out = Vec::new();
+    write_plain(&mut out, &values, json::ParseOptions::default()).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "3 items\n[\"a\",1]null");
+}
+
+#[test]
+fn io_builtins_follow_capabilities() {
+    let env = &mut Environment::builder().deny_builtin("delete-file").build();
+    run(env, r#"["import", "std::io"]"#).unwrap();
+    assert!(env.builtins.contains_key("read-file"));
+    assert!(!env.builtins.contains_key("delete-file"));
+
+    let env = &mut Environment::builder().pure().build();
+    assert!(run(env, r#"["import", "std::io"]"#).is_err());
+    assert!(!env.builtins.contains_key("write-file"));
+}